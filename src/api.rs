@@ -6,34 +6,54 @@
  *
  **/
 
-use crate::db;
+use crate::blocklist;
+use crate::config;
+use crate::diversity;
+use crate::energy;
+use crate::error::Error;
+use crate::feedback;
 use crate::forest;
+use crate::genres;
+use crate::groups;
+use crate::tonal;
+use crate::library;
+use crate::metadata;
 use crate::tree;
+use crate::weights;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::error::BlockingError;
+use actix_web::http::header;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use bliss_audio::AnalysisIndex;
 use bliss_audio::playlist::{mahalanobis_distance, variance_based_weight_matrix};
 use chrono::Datelike;
-use globset::Glob;
 use ndarray::{Array1, Array2};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng, SeedableRng};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::num::NonZero;
+use std::sync::Arc;
 use std::time::Instant;
 use strum::IntoEnumIterator;
 
-const CHRISTMAS: &str = "christmas";
-const VARIOUS: &str = "various";
-const VARIOUS_ARTISTS: &str = "various artists";
 const MIN_FOR_FOREST: usize = 4;
 const MIN_COUNT: usize = 1;
 const MAX_COUNT: usize = 50;
-const MIN_NUM_SIM: usize = 5000;
-const MAX_ARTIST_TRACKS: usize = 5;
-// KDTree is returning squared-euc distance. So max diff = sqr(0.1) = 0.01
-const MAX_ARTIST_TRACK_SIM_DIFF: f32 = 0.01;
+// Tracks of an artist are considered equally similar if their distances differ by less than
+// the spread of the closest 1% of candidates. A percentile is used so that this does not
+// depend upon the distance metric. Genre, and tonal, penalties are multiples of this spread.
+const ARTIST_TRACK_SIM_PERCENTILE: f32 = 1.0;
+const DEFAULT_GENRE_GROUPS: &str = "default";
+const DEFAULT_GENRE_PENALTY: f32 = 1.0;
+// Max penalty added to tracks that are not tonally similar
+const TONAL_PENALTY: f32 = 1.0;
+// Number of candidates to check for each step of a path
+const PATH_NUM_SIM: usize = 1000;
+// Generated random seeds are less than 2^53, so that they can be represented exactly in JSON numbers
+const MAX_RANDOM_SEED: u64 = 1 << 53;
 
 #[derive(Serialize)]
 struct AdaptiveWeightsDebug {
@@ -48,8 +68,13 @@ struct AdaptiveWeightsDebug {
 struct StatsDebug {
     db_total: usize,
     scored: usize,
+    discarded_blocked: usize,
     discarded_duration: usize,
     discarded_bpm: usize,
+    discarded_loudness: usize,
+    discarded_dynamics: usize,
+    discarded_year: usize,
+    discarded_tonal: usize,
     discarded_genre: usize,
     discarded_xmas: usize,
     discarded_album: usize,
@@ -61,7 +86,6 @@ struct StatsDebug {
 
 #[derive(Serialize)]
 struct TimingDebug {
-    db_load: u64,
     distance_calc: u64,
     sort: u64,
     filter: u64,
@@ -74,6 +98,14 @@ struct FeatureWeight {
     weight: f32,
 }
 
+// Genre groups may either be the name of groups in config, or the groups themselves
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum GenreGroups {
+    Named(String),
+    Inline(Vec<Vec<String>>),
+}
+
 #[derive(Deserialize)]
 pub struct MixParams {
     count: Option<u16>,
@@ -82,16 +114,36 @@ pub struct MixParams {
     min: Option<u32>,
     max: Option<u32>,
     maxbpmdiff: Option<i16>,
+    minbpm: Option<i16>,
+    maxbpm: Option<i16>,
+    halfdouble: Option<u16>,
+    maxloudnessdiff: Option<f32>,
+    maxdynamicsdiff: Option<f32>,
+    minyear: Option<u16>,
+    maxyear: Option<u16>,
+    maxyeardiff: Option<u16>,
     tracks: Vec<String>,
     previous: Option<Vec<String>>,
     shuffle: Option<u16>,
+    temperature: Option<f32>,
+    randomseed: Option<u64>,
     norepart: Option<u16>,
     norepalb: Option<u16>,
-    genregroups: Vec<Vec<String>>,
+    genregroups: Option<GenreGroups>,
     allgenres: Option<u16>,
     forest: Option<u16>,
     adaptiveweights: Option<u16>,
+    arc: Option<String>,
+    diversity: Option<f32>,
+    tonal: Option<String>,
+    tonalref: Option<String>,
     debug: Option<u16>,
+    genremode: Option<String>,
+    genrepenalty: Option<f32>,
+    format: Option<String>,
+    weights: Option<Vec<f32>>,
+    profile: Option<String>,
+    metric: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -102,34 +154,195 @@ pub struct ListParams {
     min: Option<u32>,
     max: Option<u32>,
     maxbpmdiff: Option<i16>,
+    minbpm: Option<i16>,
+    maxbpm: Option<i16>,
+    halfdouble: Option<u16>,
+    maxloudnessdiff: Option<f32>,
+    maxdynamicsdiff: Option<f32>,
     track: String,
-    genregroups: Vec<Vec<String>>,
+    genregroups: Option<GenreGroups>,
     allgenres: Option<u16>,
     byartist: i16,
+    genremode: Option<String>,
+    genrepenalty: Option<f32>,
+    tonal: Option<String>,
+    format: Option<String>,
+    weights: Option<Vec<f32>>,
+    profile: Option<String>,
+    metric: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PathParams {
+    count: Option<u16>,
+    filtergenre: Option<u16>,
+    filterxmas: Option<u16>,
+    min: Option<u32>,
+    max: Option<u32>,
+    maxbpmdiff: Option<i16>,
+    minbpm: Option<i16>,
+    maxbpm: Option<i16>,
+    halfdouble: Option<u16>,
+    start: String,
+    end: String,
+    previous: Option<Vec<String>>,
+    norepart: Option<u16>,
+    norepalb: Option<u16>,
+    genregroups: Option<GenreGroups>,
+    allgenres: Option<u16>,
+    format: Option<String>,
+    weights: Option<Vec<f32>>,
+    profile: Option<String>,
+    metric: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AlbumParams {
+    count: Option<u16>,
+    album: Option<String>,
+    albumartist: Option<String>,
+    path: Option<String>,
+    mintracks: Option<u16>,
+    format: Option<String>,
+    weights: Option<Vec<f32>>,
+    profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ArtistParams {
+    count: Option<u16>,
+    artist: String,
+    mintracks: Option<u16>,
+    format: Option<String>,
+    weights: Option<Vec<f32>>,
+    profile: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FeedbackParams {
+    file: String,
+    event: feedback::Event,
 }
 
+// Lowercase metadata, shared with metadata::MetadataIndex
 #[derive(Clone)]
 struct Track {
     found: bool,
     id: u64,
-    file: String,
-    title: String,
+    file: Arc<str>,
+    title: Arc<str>,
     // Original artist, so that can use for api/list
-    orig_artist: String,
-    artist: String,
-    album_artist: String,
-    album: String,
-    genres: HashSet<String>,
+    orig_artist: Arc<str>,
+    artist: Arc<str>,
+    album: Arc<str>,
+    genres: Arc<HashSet<String>>,
     duration: u32,
     sim: f32,
     is_various: bool,
-    bpm: i16
+    bpm: i16,
+    loudness: f32,
+    dynamics: f32,
+    year: u16,
 }
 
 #[derive(Clone)]
 struct TrackFile {
-    file: String,
+    id: u64,
+    file: Arc<str>,
     sim: f32,
+    seed: Option<usize>, // Index of seed this track was found from
+}
+
+#[derive(Serialize)]
+struct TrackInfo {
+    file: String,
+    title: Option<String>,
+    artist: Option<String>,
+    albumartist: Option<String>,
+    album: Option<String>,
+    genres: Vec<String>,
+    duration: u32,
+    bpm: i16,
+    year: Option<u16>,
+    distance: f32,
+    seed: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TracksResponse {
+    tracks: Vec<TrackInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    randomseed: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct AlbumInfo {
+    album: Option<String>,
+    albumartist: Option<String>,
+    path: String,
+    tracks: usize,
+    distance: f32,
+}
+
+#[derive(Serialize)]
+struct AlbumsResponse {
+    albums: Vec<AlbumInfo>,
+}
+
+#[derive(Serialize)]
+struct ArtistInfo {
+    artist: Option<String>,
+    tracks: usize,
+    distance: f32,
+}
+
+#[derive(Serialize)]
+struct ArtistsResponse {
+    artists: Vec<ArtistInfo>,
+}
+
+#[derive(Serialize)]
+struct BlocklistResponse {
+    entries: Vec<blocklist::Entry>,
+}
+
+// Penalty added to the distance of tracks whose genres are not close to those of the seeds
+struct GenrePenalty<'a> {
+    affinity: &'a genres::Affinity,
+    source: genres::Source,
+    seed_genres: HashSet<String>,
+    penalty: f32,
+}
+
+// BPM filtering options of request
+#[derive(Clone, Copy, Default)]
+struct BpmFilter {
+    maxdiff: i16, // Max difference from seed(s)
+    min: i16,
+    max: i16,
+    halfdouble: bool, // Also accept tracks whose BPM is within range when halved, or doubled
+}
+
+// Tonal similarity with reference tracks, i.e. seeds or previous track
+struct Tonal<'a> {
+    profiles: &'a tonal::Profiles,
+    mode: tonal::Mode,
+    refs: Vec<u64>,
+}
+
+// Max loudness, and dynamics, difference (dB) from seed(s). 0 to disable.
+#[derive(Clone, Copy, Default)]
+struct LevelFilter {
+    maxloudnessdiff: f32,
+    maxdynamicsdiff: f32,
+}
+
+// Range of BPM, loudness, and dynamics of seed track(s)
+#[derive(Clone, Copy)]
+struct SeedRange {
+    bpm: (i16, i16),
+    loudness: (f32, f32),
+    dynamics: (f32, f32),
 }
 
 struct MatchedArtist {
@@ -137,84 +350,64 @@ struct MatchedArtist {
     tracks: Vec<TrackFile>,
 }
 
-fn get_track_from_id(db: &db::Db, id: u64) -> Track {
-    let mut info = Track {
+fn unknown_track() -> Track {
+    let empty: Arc<str> = Arc::from("");
+    Track {
         found: false,
         id: 0,
-        file: String::new(),
-        title: String::new(),
-        artist: String::new(),
-        orig_artist: String::new(),
-        album_artist: String::new(),
-        album: String::new(),
-        genres: HashSet::new(),
+        file: empty.clone(),
+        title: empty.clone(),
+        artist: empty.clone(),
+        orig_artist: empty.clone(),
+        album: empty,
+        genres: Arc::new(HashSet::new()),
         duration: 0,
         sim: 0.,
         is_various: false,
         bpm: 0,
-    };
+        loudness: f32::NAN,
+        dynamics: f32::NAN,
+        year: 0,
+    }
+}
+
+fn get_track_from_id(metadata: &metadata::MetadataIndex, id: u64) -> Track {
+    let mut info = unknown_track();
 
-    match db.get_metadata(id) {
-        Ok(m) => {
+    match metadata.get(id) {
+        Some(m) => {
             info.id = id;
             info.found = true;
-            info.file = m.file;
-            info.title = m.title.unwrap_or_default().to_lowercase();
-            info.orig_artist = m.artist.unwrap_or_default();
-            info.artist = info.orig_artist.to_lowercase();
-            info.album_artist = m.album_artist.unwrap_or_default().to_lowercase();
-            if info.album_artist.is_empty() {
-                info.album = m.album.unwrap_or_default().to_lowercase() + "::" + &info.artist;
-            } else {
-                info.is_various =
-                    info.album_artist == VARIOUS || info.album_artist == VARIOUS_ARTISTS;
-                info.album = m.album.unwrap_or_default().to_lowercase() + "::" + &info.album_artist;
+            info.file = m.file.clone();
+            info.title = m.title_key.clone();
+            if let Some(artist) = &m.artist {
+                info.orig_artist = artist.clone();
             }
-            let genre = m.genre.unwrap_or_default();
-            let genres: Vec<&str> = genre.split(';').collect();
-            for g in genres {
-                let trimmed = g.trim();
-                if !trimmed.is_empty() {
-                    info.genres.insert(String::from(trimmed.to_lowercase()));
-                }
-            }
-            info.duration = m.duration.unwrap_or(0);
-            info.bpm = (((m.tempo.unwrap_or(0.0)+1.0)*206.0)/2.0) as i16;
+            info.artist = m.artist_key.clone();
+            info.album = m.album_key.clone();
+            info.is_various = m.is_various;
+            info.genres = m.genres.clone();
+            info.duration = m.duration;
+            info.bpm = m.bpm;
+            info.loudness = m.loudness;
+            info.dynamics = m.dynamics;
+            info.year = m.year;
         }
-        Err(e) => {
-            log::error!("Failed to read metadata. {}", e);
+        None => {
+            log::error!("Failed to read metadata for {}", id);
         }
     }
     info
 }
 
-fn get_track(db: &db::Db, track: &str) -> Track {
-    let mut info = Track {
-        found: false,
-        id: 0,
-        file: String::new(),
-        title: String::new(),
-        artist: String::new(),
-        orig_artist: String::new(),
-        album_artist: String::new(),
-        album: String::new(),
-        genres: HashSet::new(),
-        duration: 0,
-        sim: 0.,
-        is_various: false,
-        bpm: 0
-    };
-
-    let id = db.get_rowid(track);
+fn get_track(metadata: &metadata::MetadataIndex, track: &str) -> Track {
+    let id = metadata.get_rowid(track);
     if id > 0 {
-        info = get_track_from_id(db, id);
-        if !info.found {
-            log::warn!("Could not find '{}' in DB", track);
-        }
+        get_track_from_id(metadata, id)
     } else {
         log::error!("Track '{}' not found in DB", track);
+        unknown_track()
     }
-    info
 }
 
 fn get_genres(genregroups: &Vec<HashSet<String>>, track_genres: &HashSet<String>, allgenres:u16) -> HashSet<String> {
@@ -248,38 +441,59 @@ fn filter_genre(track_genres: &HashSet<String>, acceptable_genres: &HashSet<Stri
 
 fn discard_reason(
     trk: &Track,
+    blocked: &blocklist::Rules,
     min: u32,
     max: u32,
-    maxbpmdiff: i16,
-    bpm_range: Option<(i16, i16)>,
-    seed_bpm: Option<i16>,
+    bpm: &BpmFilter,
+    levels: &LevelFilter,
+    seeds: Option<&SeedRange>,
+    years: (u16, u16),
     filtergenre: u16,
     acceptable_genres: &HashSet<String>,
     all_genres_from_groups: &HashSet<String>,
-    filterxmas: u16,
-    chosen_albums: Option<&HashSet<String>>,
+    xmas: Option<&str>,
+    chosen_albums: Option<&HashSet<Arc<str>>>,
+    tonal: Option<&Tonal>,
 ) -> Option<&'static str> {
+    if blocked.is_blocked(&trk.file, &trk.artist, &trk.album, &trk.genres) {
+        return Some("blocked");
+    }
+
     if (min > 0 && trk.duration < min) || (max > 0 && trk.duration > max) {
         return Some("duration");
     }
 
-    if maxbpmdiff > 0 && trk.bpm > 0 {
-        if let Some((minbpm, maxbpm)) = bpm_range {
-            if minbpm > 0 && maxbpm > 0 && (trk.bpm < (minbpm - maxbpmdiff) || trk.bpm > (maxbpm + maxbpmdiff)) {
-                return Some("bpm");
-            }
-        } else if let Some(seed_bpm) = seed_bpm {
-            if seed_bpm > 0 && (trk.bpm - seed_bpm).abs() > maxbpmdiff {
+    if trk.bpm > 0 {
+        if (bpm.min > 0 || bpm.max > 0) && !bpm.matches(trk.bpm, bpm.min, if bpm.max > 0 { bpm.max } else { i16::MAX }) {
+            return Some("bpm");
+        }
+        if let Some(range) = seeds.filter(|_| bpm.maxdiff > 0) {
+            if range.bpm.0 > 0 && !bpm.matches(trk.bpm, range.bpm.0.saturating_sub(bpm.maxdiff), range.bpm.1.saturating_add(bpm.maxdiff)) {
                 return Some("bpm");
             }
         }
     }
 
+    // NaN, i.e. unknown, values are never outside of range
+    if let Some(range) = seeds {
+        if levels.maxloudnessdiff > 0.0 && (trk.loudness < range.loudness.0 - levels.maxloudnessdiff || trk.loudness > range.loudness.1 + levels.maxloudnessdiff) {
+            return Some("loudness");
+        }
+        if levels.maxdynamicsdiff > 0.0 && (trk.dynamics < range.dynamics.0 - levels.maxdynamicsdiff || trk.dynamics > range.dynamics.1 + levels.maxdynamicsdiff) {
+            return Some("dynamics");
+        }
+    }
+
+    // Years are (min, max), with 0 meaning no limit. Tracks without a year are not filtered.
+    if trk.year > 0 && ((years.0 > 0 && trk.year < years.0) || (years.1 > 0 && trk.year > years.1)) {
+        return Some("year");
+    }
+
     if filtergenre == 1 && filter_genre(&trk.genres, acceptable_genres, all_genres_from_groups) {
         return Some("genre");
     }
 
-    if filterxmas == 1 && trk.genres.contains(CHRISTMAS) {
+    if xmas.is_some_and(|genre| trk.genres.contains(genre)) {
         return Some("christmas");
     }
 
@@ -289,9 +503,184 @@ fn discard_reason(
         }
     }
 
+    if tonal.is_some_and(|h| h.rejects(trk.id)) {
+        return Some("tonal");
+    }
+
     None
 }
 
+impl SeedRange {
+    fn of(trk: &Track) -> Self {
+        Self {
+            bpm: (trk.bpm, trk.bpm),
+            loudness: (trk.loudness, trk.loudness),
+            dynamics: (trk.dynamics, trk.dynamics),
+        }
+    }
+
+    // Seeds with unknown BPM, loudness, or dynamics do not affect range
+    fn extend(&mut self, trk: &Track) {
+        if trk.bpm > 0 {
+            self.bpm = if self.bpm.0 > 0 { (self.bpm.0.min(trk.bpm), self.bpm.1.max(trk.bpm)) } else { (trk.bpm, trk.bpm) };
+        }
+        self.loudness = (self.loudness.0.min(trk.loudness), self.loudness.1.max(trk.loudness));
+        self.dynamics = (self.dynamics.0.min(trk.dynamics), self.dynamics.1.max(trk.dynamics));
+    }
+}
+
+impl BpmFilter {
+    fn validate(&self) -> Result<(), Error> {
+        if self.maxdiff < 0 || self.min < 0 || self.max < 0 {
+            return Err(Error::InvalidParams(String::from("maxbpmdiff, minbpm, and maxbpm must not be negative")));
+        }
+        if self.max > 0 && self.min > self.max {
+            return Err(Error::InvalidParams(String::from("minbpm must not be greater than maxbpm")));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, bpm: i16, min: i16, max: i16) -> bool {
+        let in_range = |b: i16| b >= min && b <= max;
+        in_range(bpm) || (self.halfdouble && (in_range(bpm / 2) || in_range(bpm.saturating_mul(2))))
+    }
+}
+
+impl Tonal<'_> {
+    // Best similarity with any of the reference tracks
+    fn similarity(&self, id: u64) -> f32 {
+        if self.refs.is_empty() {
+            return 1.0;
+        }
+        self.refs.iter().map(|r| self.profiles.similarity(*r, id)).fold(0.0, f32::max)
+    }
+
+    fn penalty(&self, id: u64) -> f32 {
+        match self.mode {
+            tonal::Mode::Prefer => TONAL_PENALTY * (1.0 - self.similarity(id)),
+            tonal::Mode::Require => 0.0,
+        }
+    }
+
+    fn rejects(&self, id: u64) -> bool {
+        matches!(self.mode, tonal::Mode::Require) && self.similarity(id) < tonal::MIN_SIMILARITY
+    }
+}
+
+// Get tonal mode for request. Returns None if not set.
+fn tonal<'a>(snapshot: &'a library::Snapshot, mode: &Option<String>) -> Result<Option<Tonal<'a>>, Error> {
+    match mode {
+        Some(mode) => match tonal::Mode::parse(mode) {
+            Some(mode) => Ok(Some(Tonal {
+                profiles: &snapshot.tonal,
+                mode,
+                refs: Vec::new(),
+            })),
+            None => Err(Error::InvalidParams(format!("Unknown tonal mode '{}'", mode))),
+        },
+        None => Ok(None),
+    }
+}
+
+impl GenrePenalty<'_> {
+    fn get(&self, genres: &HashSet<String>) -> f32 {
+        self.penalty * (1.0 - self.affinity.between(self.source, &self.seed_genres, genres))
+    }
+}
+
+// Get genre penalty for requested mode. Returns None for default mode, i.e. filtering on genre groups.
+// Genre affinity is built on first use, which can take a while, so is done on a blocking thread.
+async fn genre_penalty<'a>(snapshot: &'a Arc<library::Snapshot>, genremode: &Option<String>, penalty: f32) -> Result<Option<GenrePenalty<'a>>, Error> {
+    match genremode {
+        Some(mode) if !mode.eq_ignore_ascii_case("filter") => match genres::Source::parse(mode) {
+            Some(source) => {
+                if !snapshot.has_genre_affinity() {
+                    let snapshot = snapshot.clone();
+                    web::block(move || { snapshot.genre_affinity(); Ok::<_, Error>(()) }).await.map_err(|e| match e {
+                        BlockingError::Error(e) => e,
+                        BlockingError::Canceled => Error::Internal(String::from("Genre affinity build was cancelled")),
+                    })?;
+                }
+                Ok(Some(GenrePenalty {
+                    affinity: snapshot.genre_affinity(),
+                    source,
+                    seed_genres: HashSet::new(),
+                    penalty,
+                }))
+            }
+            None => Err(Error::InvalidParams(format!("Unknown genremode '{}'", mode))),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Adjust distance of a track using listener feedback, genre affinity, and tonal similarity.
+// Penalties are scaled by spread, i.e. percentile_spread of the candidates' distances.
+fn adjusted_distance(id: u64, dist: f32, factors: &HashMap<u64, f32>, metadata: &metadata::MetadataIndex, genre_penalty: &Option<GenrePenalty>, tonal: &Option<Tonal>, spread: f32) -> f32 {
+    let mut dist = dist * factors.get(&id).copied().unwrap_or(1.0);
+    if let (Some(gp), Some(m)) = (genre_penalty, metadata.get(id)) {
+        dist += spread * gp.get(&m.genres);
+    }
+    if let Some(h) = tonal {
+        dist += spread * h.penalty(id);
+    }
+    dist
+}
+
+// Adjust similarity of tracks using listener feedback, genre affinity, and tonal similarity, and re-sort
+fn rescore(mut sims: Vec<tree::Sim>, factors: &HashMap<u64, f32>, metadata: &metadata::MetadataIndex, genre_penalty: &Option<GenrePenalty>, tonal: &Option<Tonal>, spread: f32) -> Vec<tree::Sim> {
+    if factors.is_empty() && genre_penalty.is_none() && tonal.is_none() {
+        return sims;
+    }
+    for sim in sims.iter_mut() {
+        sim.sim = adjusted_distance(sim.id, sim.sim, factors, metadata, genre_penalty, tonal, spread);
+    }
+    sims.sort_by(|a, b| a.sim.total_cmp(&b.sim));
+    sims
+}
+
+// Order tracks by drawing, without replacement, with probability given by softmax of
+// (negated) distance - normalised to 0..1, so that temperature does not depend upon
+// the distance measure. Uses Gumbel-max trick, i.e. sort by perturbed log probability.
+// A temperature of 0 (or less) always orders closest first.
+fn sample(mut tracks: Vec<TrackFile>, temperature: f32, rng: &mut StdRng) -> Vec<TrackFile> {
+    if temperature <= 0.0 {
+        tracks.sort_by(|a, b| a.sim.total_cmp(&b.sim));
+        return tracks;
+    }
+    let (min_sim, max_sim) = tracks.iter().fold((f32::MAX, f32::MIN), |(lo, hi), t| (lo.min(t.sim), hi.max(t.sim)));
+    let range = if max_sim > min_sim { max_sim - min_sim } else { 1.0 };
+    let mut keyed: Vec<(f32, TrackFile)> = tracks.into_iter().map(|t| {
+        let u: f32 = rng.gen_range(f32::EPSILON..1.0);
+        (-((t.sim - min_sim) / range) / temperature - (-u.ln()).ln(), t)
+    }).collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, t)| t).collect()
+}
+
+// Difference between the closest distance and that at percentile, of sorted (unadjusted) candidates
+fn percentile_spread(sorted: &[tree::Sim], percentile: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let pos = ((sorted.len() as f32 * percentile / 100.0) as usize).min(sorted.len() - 1);
+    sorted[pos].sim - sorted[0].sim
+}
+
+// Get tree for weights, and metric. Building a tree can take a while, so this is done
+// on a blocking thread rather than holding up the worker.
+async fn get_tree(snapshot: &Arc<library::Snapshot>, weights: &weights::Weights, metric: tree::Metric) -> Result<Arc<tree::Tree>, Error> {
+    if let Some(tree) = snapshot.cached_tree(weights, metric) {
+        return Ok(tree);
+    }
+    let snapshot = snapshot.clone();
+    let weights = *weights;
+    web::block(move || Ok::<_, Error>(snapshot.tree(&weights, metric))).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => Error::Internal(String::from("Tree build was cancelled")),
+    })
+}
+
 fn log_discard(reason: &str, trk: &Track) {
     log(&format!("DISCARD({})", reason), trk);
 }
@@ -299,8 +688,13 @@ fn log_discard(reason: &str, trk: &Track) {
 impl StatsDebug {
     fn record_discard(&mut self, reason: &str) {
         match reason {
+            "blocked" => self.discarded_blocked += 1,
             "duration" => self.discarded_duration += 1,
             "bpm" => self.discarded_bpm += 1,
+            "loudness" => self.discarded_loudness += 1,
+            "dynamics" => self.discarded_dynamics += 1,
+            "year" => self.discarded_year += 1,
+            "tonal" => self.discarded_tonal += 1,
             "genre" => self.discarded_genre += 1,
             "christmas" => self.discarded_xmas += 1,
             "album" => self.discarded_album += 1,
@@ -309,55 +703,195 @@ impl StatsDebug {
     }
 }
 
-fn expand_globbed_genres(genregroups: &Vec<Vec<String>>, all_db_genres: &HashSet<String>) -> Vec<HashSet<String>> {
-    let mut expanded: Vec<HashSet<String>> = Vec::new();
+// Get genre groups for request. If none specified, use groups named 'default' in config (if any)
+fn resolve_genre_groups(snapshot: &library::Snapshot, groups: &Option<GenreGroups>) -> Result<Arc<Vec<HashSet<String>>>, Error> {
+    match groups {
+        Some(GenreGroups::Inline(groups)) => Ok(Arc::new(library::expand_globbed_genres(groups, &snapshot.all_db_genres)?)),
+        Some(GenreGroups::Named(name)) => snapshot.genre_groups(name).ok_or_else(|| Error::UnknownGenreGroups(name.clone())),
+        None => Ok(snapshot.genre_groups(DEFAULT_GENRE_GROUPS).unwrap_or_default()),
+    }
+}
 
-    for group in genregroups {
-        let mut gset: HashSet<String> = HashSet::new();
-        for genre in group {
-            let lgenre = genre.to_lowercase();
-            let glob = Glob::new(&lgenre).unwrap().compile_matcher();
-            for item in all_db_genres {
-                if glob.is_match(item) {
-                    gset.insert(item.to_string());
+fn wants_json(req: &HttpRequest, format: &Option<String>) -> bool {
+    if let Some(fmt) = format {
+        return fmt.eq_ignore_ascii_case("json");
+    }
+    match req.headers().get(header::ACCEPT) {
+        Some(accept) => accept.to_str().unwrap_or_default().contains("application/json"),
+        None => false
+    }
+}
+
+fn send_tracks(mut http_resp: HttpResponseBuilder, metadata: &metadata::MetadataIndex, tracks: &[TrackFile], seed_files: &[Arc<str>], randomseed: Option<u64>, json: bool) -> HttpResponse {
+    if let Some(seed) = randomseed {
+        http_resp.set_header("X-Bliss-Random-Seed", seed.to_string());
+    }
+    if json {
+        let mut resp = TracksResponse { tracks: Vec::new(), randomseed };
+        for track in tracks {
+            let mut info = TrackInfo {
+                file: track.file.to_string(),
+                title: None,
+                artist: None,
+                albumartist: None,
+                album: None,
+                genres: Vec::new(),
+                duration: 0,
+                bpm: 0,
+                year: None,
+                distance: track.sim,
+                seed: track.seed.and_then(|s| seed_files.get(s)).map(|s| s.to_string()),
+            };
+            match metadata.get(track.id) {
+                Some(m) => {
+                    info.title = m.title.as_deref().map(String::from);
+                    info.artist = m.artist.as_deref().map(String::from);
+                    info.albumartist = m.album_artist.as_deref().map(String::from);
+                    info.album = m.album.as_deref().map(String::from);
+                    info.genres = m.genre_names.to_vec();
+                    info.duration = m.duration;
+                    info.bpm = m.bpm;
+                    info.year = if m.year > 0 { Some(m.year) } else { None };
+                }
+                None => {
+                    log::error!("Failed to read metadata for {}", track.id);
                 }
             }
+            resp.tracks.push(info);
         }
-        expanded.push(gset);
+        return http_resp.json(resp);
     }
-    expanded
+
+    let mut resp = String::new();
+    for track in tracks {
+        resp += &track.file;
+        resp += "\n";
+    }
+    http_resp.content_type("text/plain; charset=utf-8");
+    http_resp.body(resp)
+}
+
+fn send_albums(mut http_resp: HttpResponseBuilder, albums: &[groups::Similar], json: bool) -> HttpResponse {
+    if json {
+        let resp = AlbumsResponse {
+            albums: albums.iter().map(|a| AlbumInfo {
+                album: a.group.name.as_deref().map(String::from),
+                albumartist: a.group.artist.as_deref().map(String::from),
+                path: a.group.path.clone(),
+                tracks: a.group.num_tracks,
+                distance: a.sim,
+            }).collect(),
+        };
+        return http_resp.json(resp);
+    }
+
+    let mut resp = String::new();
+    for album in albums {
+        resp.push_str(&album.group.path);
+        resp.push('\n');
+    }
+    http_resp.content_type("text/plain; charset=utf-8");
+    http_resp.body(resp)
+}
+
+fn send_artists(mut http_resp: HttpResponseBuilder, artists: &[groups::Similar], json: bool) -> HttpResponse {
+    if json {
+        let resp = ArtistsResponse {
+            artists: artists.iter().map(|a| ArtistInfo {
+                artist: a.group.name.as_deref().map(String::from),
+                tracks: a.group.num_tracks,
+                distance: a.sim,
+            }).collect(),
+        };
+        return http_resp.json(resp);
+    }
+
+    let mut resp = String::new();
+    for artist in artists {
+        resp.push_str(artist.group.name.as_deref().unwrap_or_default());
+        resp.push('\n');
+    }
+    http_resp.content_type("text/plain; charset=utf-8");
+    http_resp.body(resp)
 }
 
 fn log(reason: &str, trk: &Track) {
     log::debug!("{} File:{}, Title:{}, Album/Artist:{}, Dur:{}, Sim:{:.18}, Genres:{:?}, BPM:{}", reason, trk.file, trk.title, trk.album, trk.duration, trk.sim, trk.genres, trk.bpm);
 }
 
-pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpResponse {
-    let tree = req.app_data::<web::Data<tree::Tree>>().unwrap();
-    let all_db_genres = req.app_data::<web::Data<HashSet<String>>>().unwrap();
-    let db_path = req.app_data::<web::Data<String>>().unwrap();
-    let db = db::Db::new(db_path);
-    let mut count = payload.count.unwrap_or(5) as usize;
-    let filtergenre = payload.filtergenre.unwrap_or(0);
-    let mut filterxmas = payload.filterxmas.unwrap_or(0);
-    let min = payload.min.unwrap_or(0);
-    let max = payload.max.unwrap_or(0);
-    let maxbpmdiff = payload.maxbpmdiff.unwrap_or(0);
-    let shuffle = payload.shuffle.unwrap_or(0);
-    let norepart = payload.norepart.unwrap_or(0);
-    let norepalb = payload.norepalb.unwrap_or(0);
-    let genregroups = expand_globbed_genres(&payload.genregroups, &all_db_genres);
-    let allgenres = payload.allgenres.unwrap_or(0);
-    let mut useforest = payload.forest.unwrap_or(0);
-    let useadaptiveweights = payload.adaptiveweights.unwrap_or(0);
+pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let snapshot = library.snapshot();
+    if snapshot.is_empty() {
+        return Err(Error::NotLoaded);
+    }
+    let config = req.app_data::<web::Data<config::Settings>>().unwrap().get();
+    let defaults = &config.mix;
+    let profile = payload.profile.clone().or(defaults.profile.clone());
+    let weights = library.weights(&profile, &payload.weights)?;
+    let metric = library.metric(&profile, &payload.metric.clone().or(defaults.metric.clone()))?;
+    let tree = get_tree(&snapshot, &weights, metric).await?;
+    let blocked = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap().rules();
+    let factors = req.app_data::<web::Data<feedback::Feedback>>().unwrap().factors(&snapshot, library.default_weights());
+    let mut count = payload.count.or(defaults.count).unwrap_or(5) as usize;
+    let filtergenre = payload.filtergenre.or(defaults.filtergenre).unwrap_or(0);
+    let genrepenalty = payload.genrepenalty.or(defaults.genrepenalty).unwrap_or(DEFAULT_GENRE_PENALTY);
+    let mut genre_penalty = genre_penalty(&snapshot, &payload.genremode.clone().or(defaults.genremode.clone()), genrepenalty).await?;
+    let mut tonal = tonal(&snapshot, &payload.tonal.clone().or(defaults.tonal.clone()))?;
+    let tonalref = payload.tonalref.clone().or(defaults.tonalref.clone()).unwrap_or(String::from("seed"));
+    if tonalref != "seed" && tonalref != "previous" {
+        return Err(Error::InvalidParams(format!("Unknown tonalref '{}'", tonalref)));
+    }
+    // With genre affinity, tracks are penalised rather than filtered - except for forest, which has no distances
+    let hardfiltergenre = if genre_penalty.is_some() { 0 } else { filtergenre };
+    let mut filterxmas = payload.filterxmas.or(defaults.filterxmas).unwrap_or(0);
+    let min = payload.min.or(defaults.min).unwrap_or(0);
+    let max = payload.max.or(defaults.max).unwrap_or(0);
+    let bpm = BpmFilter {
+        maxdiff: payload.maxbpmdiff.or(defaults.maxbpmdiff).unwrap_or(0),
+        min: payload.minbpm.or(defaults.minbpm).unwrap_or(0),
+        max: payload.maxbpm.or(defaults.maxbpm).unwrap_or(0),
+        halfdouble: payload.halfdouble.or(defaults.halfdouble).unwrap_or(0) == 1,
+    };
+    bpm.validate()?;
+    let levels = LevelFilter {
+        maxloudnessdiff: payload.maxloudnessdiff.or(defaults.maxloudnessdiff).unwrap_or(0.0),
+        maxdynamicsdiff: payload.maxdynamicsdiff.or(defaults.maxdynamicsdiff).unwrap_or(0.0),
+    };
+    let minyear = payload.minyear.or(defaults.minyear).unwrap_or(0);
+    let maxyear = payload.maxyear.or(defaults.maxyear).unwrap_or(0);
+    let maxyeardiff = payload.maxyeardiff.or(defaults.maxyeardiff).unwrap_or(0);
+    let shuffle = payload.shuffle.or(defaults.shuffle).unwrap_or(0);
+    let temperature = payload.temperature.or(defaults.temperature);
+    if temperature.is_some_and(|t| t.is_nan() || t < 0.0) {
+        return Err(Error::InvalidParams(String::from("temperature must not be negative")));
+    }
+    // Seed is returned in response, so that shuffled mixes can be reproduced
+    let randomseed = payload.randomseed.unwrap_or_else(|| thread_rng().gen_range(0..MAX_RANDOM_SEED));
+    let mut rng = StdRng::seed_from_u64(randomseed);
+    let norepart = payload.norepart.or(defaults.norepart).unwrap_or(0);
+    let norepalb = payload.norepalb.or(defaults.norepalb).unwrap_or(0);
+    let genregroups = resolve_genre_groups(&snapshot, &payload.genregroups)?;
+    let allgenres = payload.allgenres.or(defaults.allgenres).unwrap_or(0);
+    let mut useforest = payload.forest.or(defaults.forest).unwrap_or(0);
+    let useadaptiveweights = payload.adaptiveweights.or(defaults.adaptiveweights).unwrap_or(0);
+    let arc = match payload.arc.clone().or(defaults.arc.clone()) {
+        Some(val) => Some(energy::Shape::parse(&val).ok_or_else(|| Error::InvalidParams(format!("Unknown arc '{}'", val)))?),
+        None => None,
+    };
+    let diversity = payload.diversity.or(defaults.diversity).unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&diversity) {
+        return Err(Error::InvalidParams(String::from("diversity must be between 0 and 1")));
+    }
     let wantdebug = payload.debug.unwrap_or(0) == 1;
+    let wantjson = wants_json(&req, &payload.format.clone().or(defaults.format.clone()));
     let mut seeds: Vec<Track> = Vec::new();
     // Tracks filtered out due to title matching seed or chosen track
-    let mut filter_out_titles: HashSet<String> = HashSet::new();
+    let mut filter_out_titles: HashSet<Arc<str>> = HashSet::new();
     // Tracks filtered out due to artist matching seed or chosen track
-    let mut filter_out_artists: HashSet<String> = HashSet::new();
+    let mut filter_out_artists: HashSet<Arc<str>> = HashSet::new();
     // Tracks filtered out due to album matching seed or chosen track
-    let mut filter_out_albums: HashSet<String> = HashSet::new();
+    let mut filter_out_albums: HashSet<Arc<str>> = HashSet::new();
     // IDs of seeds, previous, and chosen tracks - to prevent duplicates
     let mut filter_out_ids: HashSet<u64> = HashSet::new();
     // All acceptable genres
@@ -366,7 +900,7 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
     let mut all_genres_from_groups: HashSet<String> = HashSet::new();
     // Albums from matching tracks. Don't want same album chosen twice, even if
     // norepalb is 0 or album is a VA album.
-    let mut chosen_albums: HashSet<String> = HashSet::new();
+    let mut chosen_albums: HashSet<Arc<str>> = HashSet::new();
     let mut debug_info: Option<AdaptiveWeightsDebug> = None;
 
     if count < MIN_COUNT {
@@ -378,22 +912,25 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
     if filterxmas == 1 && chrono::Local::now().month() == 12 {
         filterxmas = 0;
     }
+    let xmas = if filterxmas == 1 { Some(config.xmasgenre.as_str()) } else { None };
 
-    for group in &genregroups {
+    for group in genregroups.iter() {
         for genre in group {
             all_genres_from_groups.insert(genre.to_string());
         }
     }
 
     // Find previous in DB
+    let mut last_previous: Option<u64> = None;
     if let Some(previous) = &payload.previous {
         let mut pcount = 0;
         for track in previous {
-            let trk: Track = get_track(&db, track);
+            let trk: Track = get_track(&snapshot.metadata, track);
             if !trk.found {
                 continue;
             }
             filter_out_ids.insert(trk.id);
+            last_previous = Some(trk.id);
             if !trk.title.is_empty() {
                 filter_out_titles.insert(trk.title);
             }
@@ -411,12 +948,13 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
         }
     }
 
-    let mut minbpm: i16 = 500;
-    let mut maxbpm: i16 = 0;
+    let mut seed_range: Option<SeedRange> = None;
+    let mut minseedyear: u16 = u16::MAX;
+    let mut maxseedyear: u16 = 0;
 
     // Find seeds in DB
     for track in &payload.tracks {
-        let trk: Track = get_track(&db, track);
+        let trk: Track = get_track(&snapshot.metadata, track);
         if !trk.found {
             continue;
         }
@@ -424,20 +962,45 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
             let genres = get_genres(&genregroups, &trk.genres, allgenres);
             acceptable_genres.extend(genres.clone());
         }
+        if let Some(gp) = genre_penalty.as_mut() {
+            gp.seed_genres.extend(trk.genres.iter().cloned());
+        }
         filter_out_ids.insert(trk.id);
         if !trk.title.is_empty() {
             filter_out_titles.insert(trk.title.clone());
         }
-        if trk.bpm>maxbpm {
-            maxbpm = trk.bpm
+        match seed_range.as_mut() {
+            Some(range) => range.extend(&trk),
+            None => { seed_range = Some(SeedRange::of(&trk)); }
         }
-        if trk.bpm<minbpm {
-            minbpm = trk.bpm
+        if trk.year > 0 {
+            minseedyear = minseedyear.min(trk.year);
+            maxseedyear = maxseedyear.max(trk.year);
         }
         seeds.push(trk);
     }
+    if seeds.is_empty() {
+        return Err(Error::UnknownTrack(payload.tracks.join(", ")));
+    }
+    let seed_files: Vec<Arc<str>> = seeds.iter().map(|s| s.file.clone()).collect();
 
-    log::debug!("filtergenre:{}, filterxmas:{}, min:{}, max:{}, shuffle:{}, norepart:{}, norepalb:{}", filtergenre, filterxmas, min, max, shuffle, norepart, norepalb);
+    // Compare tonally with last of previous tracks, if wanted - otherwise with seeds
+    if let Some(h) = tonal.as_mut() {
+        h.refs = match last_previous {
+            Some(id) if tonalref == "previous" => vec![id],
+            _ => seeds.iter().map(|s| s.id).collect(),
+        };
+    }
+
+    // Range of acceptable years, narrowed to those near the seeds' years (if known)
+    let mut years = (minyear, maxyear);
+    if maxyeardiff > 0 && maxseedyear > 0 {
+        years.0 = years.0.max(minseedyear.saturating_sub(maxyeardiff));
+        let maxseedyear = maxseedyear.saturating_add(maxyeardiff);
+        years.1 = if years.1 > 0 { years.1.min(maxseedyear) } else { maxseedyear };
+    }
+
+    log::debug!("filtergenre:{}, filterxmas:{}, min:{}, max:{}, years:{:?}, shuffle:{}, norepart:{}, norepalb:{}", filtergenre, filterxmas, min, max, years, shuffle, norepart, norepalb);
 
     if filtergenre == 1 {
         log::debug!("Acceptable genres: {:?}", acceptable_genres);
@@ -449,26 +1012,33 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
     let mut filtered: Vec<TrackFile> = Vec::new();
     // Map of artist name to list of similar tracks. If we are shuffling
     // and an artist has multiple similar tracks we will choose one at random
-    let mut matched_artists: HashMap<String, MatchedArtist> = HashMap::new();
+    let mut matched_artists: HashMap<Arc<str>, MatchedArtist> = HashMap::new();
     // How many simlar tracks should we locate in total?
     let mut similarity_count: usize = count;
-    if shuffle == 1 && count < 20 {
+    if (shuffle == 1 || temperature.is_some() || arc.is_some() || diversity > 0.0) && count < 20 {
         similarity_count = count * 5;
     }
 
     let mut fseeds: Vec<forest::Track> = Vec::new();
     if useforest>0 && useadaptiveweights==0 && seeds.len()>=MIN_FOR_FOREST {
         for seed in seeds.clone() {
-            if let Ok(metrics) = db.get_metrics(seed.id) {
-                let track = forest::Track {
-                    id: seed.id,
-                    metrics: metrics,
-                };
-                fseeds.push(track)
-            }
+            let metrics = snapshot.metrics(seed.id, &weights)?;
+            let track = forest::Track {
+                id: seed.id,
+                metrics: metrics,
+            };
+            fseeds.push(track)
         }
     }
 
+    // How many similar tracks should we consider? For the standard algorithm this is the number
+    // to get from the KDTree for each seed, and for both algorithms the closest this many are
+    // used to determine the spread of candidates' distances.
+    let mut num_sim = count * seeds.len() * 50;
+    if num_sim < config.numsim {
+        num_sim = config.numsim;
+    }
+
     if useadaptiveweights == 1 {
         // Adaptive weighting: compute weight matrix from seed variance, then
         // scan and re-rank candidates with adaptive distances.
@@ -476,9 +1046,7 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
         // Collect raw (unweighted) metrics for all seeds
         let mut seed_raw_metrics: Vec<[f32; tree::DIMENSIONS]> = Vec::new();
         for seed in &seeds {
-            if let Ok(raw) = db.get_raw_metrics(seed.id) {
-                seed_raw_metrics.push(raw);
-            }
+            seed_raw_metrics.push(snapshot.raw_metrics(seed.id)?);
         }
 
         // Determine the weight matrix to use
@@ -525,24 +1093,18 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
                 mean_raw[i] /= seed_raw_metrics.len() as f32;
             }
 
-            // Full DB scan: compute Mahalanobis distance for every track
-            let t_db = Instant::now();
-            let all_raw = db.get_all_raw_metrics();
-            let db_load_ms = t_db.elapsed().as_millis() as u64;
-            log::debug!("DB load: {} tracks in {}ms", all_raw.len(), db_load_ms);
-
-            // Score all tracks using adaptive Mahalanobis distance
+            // Full scan: compute Mahalanobis distance for every track
             let t_dist = Instant::now();
             let mean_arr = Array1::from_vec(mean_raw.to_vec());
-            let mut scored: Vec<(u64, f32)> = all_raw
+            let mut scored: Vec<tree::Sim> = snapshot.raw.ids
                 .par_iter()
+                .zip(snapshot.raw.values.par_iter())
                 .filter_map(|(id, raw)| {
                     if filter_out_ids.contains(id) {
                         None
                     } else {
                         let raw_arr = Array1::from_vec(raw.to_vec());
-                        let dist = mahalanobis_distance(&mean_arr, &raw_arr, matrix);
-                        Some((*id, dist))
+                        Some(tree::Sim { id: *id, sim: mahalanobis_distance(&mean_arr, &raw_arr, matrix) })
                     }
                 })
                 .collect();
@@ -551,17 +1113,32 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
             log::debug!("Distance calculation: {} tracks scored in {}ms", scored_count, distance_calc_ms);
 
             let t_sort = Instant::now();
-            scored.sort_by(|a, b| a.1.total_cmp(&b.1));
+            // Spread is taken from the closest num_sim candidates, as per the standard algorithm
+            let num_closest = num_sim.min(scored_count);
+            if num_closest > 0 && num_closest < scored_count {
+                scored.select_nth_unstable_by(num_closest - 1, |a, b| a.sim.total_cmp(&b.sim));
+            }
+            scored[..num_closest].sort_by(|a, b| a.sim.total_cmp(&b.sim));
+            let max_artist_sim_diff = percentile_spread(&scored[..num_closest], ARTIST_TRACK_SIM_PERCENTILE);
+            scored.par_iter_mut().for_each(|s| {
+                s.sim = adjusted_distance(s.id, s.sim, &factors, &snapshot.metadata, &genre_penalty, &tonal, max_artist_sim_diff);
+            });
+            scored.sort_by(|a, b| a.sim.total_cmp(&b.sim));
             let sort_ms = t_sort.elapsed().as_millis() as u64;
             log::debug!("Sort: {}ms", sort_ms);
 
             // Apply filters and build chosen list
             let t_filter = Instant::now();
             let mut stats = StatsDebug {
-                db_total: all_raw.len(),
+                db_total: snapshot.raw.ids.len(),
                 scored: scored_count,
+                discarded_blocked: 0,
                 discarded_duration: 0,
                 discarded_bpm: 0,
+                discarded_loudness: 0,
+                discarded_dynamics: 0,
+                discarded_year: 0,
+                discarded_tonal: 0,
                 discarded_genre: 0,
                 discarded_xmas: 0,
                 discarded_album: 0,
@@ -570,30 +1147,35 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
                 filtered_title: 0,
                 usable: 0,
             };
-            for (cid, dist) in scored {
+            for tree::Sim { id: cid, sim: dist } in scored {
                 filter_out_ids.insert(cid);
-                let mut trk: Track = get_track_from_id(&db, cid);
+                let mut trk: Track = get_track_from_id(&snapshot.metadata, cid);
                 trk.sim = dist;
                 if let Some(reason) = discard_reason(
                     &trk,
+                    &blocked,
                     min,
                     max,
-                    maxbpmdiff,
-                    Some((minbpm, maxbpm)),
-                    None,
-                    filtergenre,
+                    &bpm,
+                    &levels,
+                    seed_range.as_ref(),
+                    years,
+                    hardfiltergenre,
                     &acceptable_genres,
                     &all_genres_from_groups,
-                    filterxmas,
+                    xmas,
                     Some(&chosen_albums),
+                    tonal.as_ref(),
                 ) {
                     log_discard(reason, &trk);
                     stats.record_discard(reason);
                     continue;
                 }
                 let track_file = TrackFile {
+                    id: trk.id,
                     file: trk.file.clone(),
                     sim: trk.sim,
+                    seed: None,
                 };
                 if norepart > 0 && filter_out_artists.contains(&trk.artist) {
                     log("FILTER(artist)", &trk);
@@ -602,7 +1184,7 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
                     if shuffle == 1 {
                         match matched_artists.get_mut(&trk.artist) {
                             Some(artist) => {
-                                if artist.tracks.len() < MAX_ARTIST_TRACKS && (dist - artist.tracks[0].sim).abs() < MAX_ARTIST_TRACK_SIM_DIFF {
+                                if artist.tracks.len() < config.maxartisttracks && (dist - artist.tracks[0].sim).abs() < max_artist_sim_diff {
                                     artist.tracks.push(track_file.clone())
                                 }
                             }
@@ -668,7 +1250,6 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
                     weights: feature_weights,
                     stats,
                     timing_ms: TimingDebug {
-                        db_load: db_load_ms,
                         distance_calc: distance_calc_ms,
                         sort: sort_ms,
                         filter: filter_ms,
@@ -692,47 +1273,52 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
         let mut forest_ids: HashSet<u64> = HashSet::new();
         let num_per_file = ((10000/fseeds.len()) as usize).min(1000);
         for seed in seeds {
-            if let Ok(metrics) = db.get_metrics(seed.id) {
-                log::debug!("Looking for {} tracks similar to '{}'", num_per_file, seed.file);
-                let sim_tracks = tree.get_similars(&metrics, NonZero::new(num_per_file).unwrap());
-                for sim_track in sim_tracks {
-                    if !forest_ids.contains(&sim_track.id) {
-                        if let Ok(smetrics) = db.get_metrics(sim_track.id) {
-                            forest.values.push(smetrics);
-                            forest.ids.push(sim_track.id);
-                            forest_ids.insert(sim_track.id);
-                        }
+            let metrics = snapshot.metrics(seed.id, &weights)?;
+            log::debug!("Looking for {} tracks similar to '{}'", num_per_file, seed.file);
+            let sim_tracks = tree.get_similars(&metrics, NonZero::new(num_per_file).unwrap());
+            for sim_track in sim_tracks {
+                if !forest_ids.contains(&sim_track.id) {
+                    if let Ok(smetrics) = snapshot.metrics(sim_track.id, &weights) {
+                        forest.values.push(smetrics);
+                        forest.ids.push(sim_track.id);
+                        forest_ids.insert(sim_track.id);
                     }
                 }
             }
         }
 
         log::debug!("Forest size: {}", forest.values.len());
-        for track in forest::sort_by_closest(&forest, &fseeds) {
+        // Feedback factors are applied to forest scores, as there are no distances
+        for track in rescore(forest::sort_by_closest(&forest, &fseeds)?, &factors, &snapshot.metadata, &None, &None, 0.0) {
             if filter_out_ids.contains(&track.id) {
                 continue;
             }
             filter_out_ids.insert(track.id);
-            let trk: Track = get_track_from_id(&db, track.id);
+            let trk: Track = get_track_from_id(&snapshot.metadata, track.id);
             if let Some(reason) = discard_reason(
                 &trk,
+                &blocked,
                 min,
                 max,
-                maxbpmdiff,
-                Some((minbpm, maxbpm)),
-                None,
+                &bpm,
+                &levels,
+                seed_range.as_ref(),
+                years,
                 filtergenre,
                 &acceptable_genres,
                 &all_genres_from_groups,
-                filterxmas,
+                xmas,
                 Some(&chosen_albums),
+                tonal.as_ref(),
             ) {
                 log_discard(reason, &trk);
                 continue;
             }
             let track_file = TrackFile {
+                id: trk.id,
                 file: trk.file.clone(),
-                sim: 1.0,
+                sim: track.sim,
+                seed: None,
             };
             if norepart > 0 && filter_out_artists.contains(&trk.artist) {
                 log("FILTER(artist)", &trk);
@@ -779,110 +1365,112 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
             tracks_per_seed = similarity_count * 3;
         }
 
-        // How many similar tracks should we get from KDTree?
-        let mut num_sim = count * seeds.len() * 50;
-        if num_sim < MIN_NUM_SIM {
-            num_sim = MIN_NUM_SIM;
-        }
-
-        for seed in seeds {
+        for (seed_idx, seed) in seeds.into_iter().enumerate() {
             let mut accepted_for_seed = 0;
-            if let Ok(metrics) = db.get_metrics(seed.id) {
-                log::debug!("Looking for tracks similar to '{}'", seed.file);
-                let sim_tracks = tree.get_similars(&metrics, NonZero::new(num_sim).unwrap());
-                for sim_track in sim_tracks {
-                    if filter_out_ids.contains(&sim_track.id) {
-                        // Seen from previous seed, so set similarity to lowest value
-                        match id_to_pos.get(&sim_track.id) {
-                            Some(pos) => {
-                                if chosen[*pos].sim > sim_track.sim {
-                                    chosen[*pos].sim = sim_track.sim;
-                                }
+            let range = SeedRange::of(&seed);
+            let metrics = snapshot.metrics(seed.id, &weights)?;
+            log::debug!("Looking for tracks similar to '{}'", seed.file);
+            let sim_tracks = tree.get_similars(&metrics, NonZero::new(num_sim).unwrap());
+            let max_artist_sim_diff = percentile_spread(&sim_tracks, ARTIST_TRACK_SIM_PERCENTILE);
+            let sim_tracks = rescore(sim_tracks, &factors, &snapshot.metadata, &genre_penalty, &tonal, max_artist_sim_diff);
+            for sim_track in sim_tracks {
+                if filter_out_ids.contains(&sim_track.id) {
+                    // Seen from previous seed, so set similarity to lowest value
+                    match id_to_pos.get(&sim_track.id) {
+                        Some(pos) => {
+                            if chosen[*pos].sim > sim_track.sim {
+                                chosen[*pos].sim = sim_track.sim;
+                                chosen[*pos].seed = Some(seed_idx);
                             }
-                            None => {}
-                        }
-                    } else {
-                        filter_out_ids.insert(sim_track.id);
-                        let mut trk: Track = get_track_from_id(&db, sim_track.id);
-                        trk.sim = sim_track.sim;
-                        if let Some(reason) = discard_reason(
-                            &trk,
-                            min,
-                            max,
-                            maxbpmdiff,
-                            None,
-                            Some(seed.bpm),
-                            filtergenre,
-                            &acceptable_genres,
-                            &all_genres_from_groups,
-                            filterxmas,
-                            Some(&chosen_albums),
-                        ) {
-                            log_discard(reason, &trk);
-                            continue;
                         }
-                        let track_file = TrackFile {
-                            file: trk.file.clone(),
-                            sim: trk.sim,
-                        };
-                        if norepart > 0 && filter_out_artists.contains(&trk.artist) {
-                            log("FILTER(artist)", &trk);
-
-                            if shuffle == 1 {
-                                // We have seen this artist before. If this track is close in similarity
-                                // to the first from this artist then store it - we will choose a random
-                                // track later.
-                                match matched_artists.get_mut(&trk.artist) {
-                                    Some(artist) => {
-                                        if artist.tracks.len() < MAX_ARTIST_TRACKS && (sim_track.sim - artist.tracks[0].sim).abs() < MAX_ARTIST_TRACK_SIM_DIFF {
-                                            artist.tracks.push(track_file.clone())
-                                        }
+                        None => {}
+                    }
+                } else {
+                    filter_out_ids.insert(sim_track.id);
+                    let mut trk: Track = get_track_from_id(&snapshot.metadata, sim_track.id);
+                    trk.sim = sim_track.sim;
+                    if let Some(reason) = discard_reason(
+                        &trk,
+                        &blocked,
+                        min,
+                        max,
+                        &bpm,
+                        &levels,
+                        Some(&range),
+                        years,
+                        hardfiltergenre,
+                        &acceptable_genres,
+                        &all_genres_from_groups,
+                        xmas,
+                        Some(&chosen_albums),
+                        tonal.as_ref(),
+                    ) {
+                        log_discard(reason, &trk);
+                        continue;
+                    }
+                    let track_file = TrackFile {
+                        id: trk.id,
+                        file: trk.file.clone(),
+                        sim: trk.sim,
+                        seed: Some(seed_idx),
+                    };
+                    if norepart > 0 && filter_out_artists.contains(&trk.artist) {
+                        log("FILTER(artist)", &trk);
+
+                        if shuffle == 1 {
+                            // We have seen this artist before. If this track is close in similarity
+                            // to the first from this artist then store it - we will choose a random
+                            // track later.
+                            match matched_artists.get_mut(&trk.artist) {
+                                Some(artist) => {
+                                    if artist.tracks.len() < config.maxartisttracks && (sim_track.sim - artist.tracks[0].sim).abs() < max_artist_sim_diff {
+                                        artist.tracks.push(track_file.clone())
                                     }
-                                    None => {}
                                 }
+                                None => {}
                             }
-
-                            filtered.push(track_file);
-                            continue;
-                        }
-                        if !trk.is_various && norepalb > 0 && filter_out_albums.contains(&trk.album) {
-                            log("FILTER(album)", &trk);
-                            filtered.push(track_file);
-                            continue;
-                        }
-                        if filter_out_titles.contains(&trk.title) {
-                            log("FILTER(title)", &trk);
-                            filtered.push(track_file);
-                            continue;
                         }
-                        log("USABLE", &trk);
-                        filter_out_titles.insert(trk.title.clone());
-                        if norepart > 0 {
-                            filter_out_artists.insert(trk.artist.clone());
-                        }
-                        if norepalb > 0 {
-                            filter_out_albums.insert(trk.album.clone());
-                        }
-                        chosen_albums.insert(trk.album.clone());
-                        id_to_pos.insert(trk.id, chosen.len());
-                        chosen.push(track_file.clone());
 
-                        if shuffle == 1 {
-                            // Store this track linked to artist. Next time we see artist we
-                            // will extend this list of tracks so that we can choose a random
-                            // one later.
-                            let mut matched_artist = MatchedArtist {
-                                pos: chosen.len() - 1,
-                                tracks: Vec::new(),
-                            };
-                            matched_artist.tracks.push(track_file);
-                            matched_artists.insert(trk.artist.clone(), matched_artist);
-                        }
+                        filtered.push(track_file);
+                        continue;
+                    }
+                    if !trk.is_various && norepalb > 0 && filter_out_albums.contains(&trk.album) {
+                        log("FILTER(album)", &trk);
+                        filtered.push(track_file);
+                        continue;
+                    }
+                    if filter_out_titles.contains(&trk.title) {
+                        log("FILTER(title)", &trk);
+                        filtered.push(track_file);
+                        continue;
+                    }
+                    log("USABLE", &trk);
+                    filter_out_titles.insert(trk.title.clone());
+                    if norepart > 0 {
+                        filter_out_artists.insert(trk.artist.clone());
+                    }
+                    if norepalb > 0 {
+                        filter_out_albums.insert(trk.album.clone());
+                    }
+                    chosen_albums.insert(trk.album.clone());
+                    id_to_pos.insert(trk.id, chosen.len());
+                    chosen.push(track_file.clone());
 
-                        accepted_for_seed += 1;
-                        if accepted_for_seed >= tracks_per_seed {
-                            break;
-                        }
+                    if shuffle == 1 {
+                        // Store this track linked to artist. Next time we see artist we
+                        // will extend this list of tracks so that we can choose a random
+                        // one later.
+                        let mut matched_artist = MatchedArtist {
+                            pos: chosen.len() - 1,
+                            tracks: Vec::new(),
+                        };
+                        matched_artist.tracks.push(track_file);
+                        matched_artists.insert(trk.artist.clone(), matched_artist);
+                    }
+
+                    accepted_for_seed += 1;
+                    if accepted_for_seed >= tracks_per_seed {
+                        break;
                     }
                 }
             }
@@ -890,8 +1478,6 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
     }
     } // end forest/standard fallback
 
-    db.close();
-
     log::debug!("similar_tracks: {}, filtered_tracks:{}", chosen.len(), filtered.len());
     if useforest!=1 {
         let mut min_count: usize = 2;
@@ -900,13 +1486,16 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
         }
 
         if shuffle == 1  {
-            // For each artist that had multiple similar tracks, choose one at random
+            // For each artist that had multiple similar tracks, choose one at random. Artists
+            // are sorted so that the same random seed gives the same choices.
+            let mut matched_artists: Vec<(Arc<str>, MatchedArtist)> = matched_artists.into_iter().collect();
+            matched_artists.sort_by_key(|(_, info)| info.pos);
             for (name, info) in matched_artists {
                 if info.tracks.len() > 1 {
                     log::debug!("Choosing random track for {} ({} tracks)", name, info.tracks.len());
-                    match info.tracks.choose(&mut thread_rng()) {
+                    match info.tracks.choose(&mut rng) {
                         Some(trk) => {
-                            chosen[info.pos].file = trk.file.clone();
+                            chosen[info.pos] = trk.clone();
                         }
                         None => {}
                     }
@@ -926,22 +1515,38 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
     // Sort by similarity
     chosen.sort_by(|a, b| a.sim.total_cmp(&b.sim));
 
-    if shuffle == 1 {
+    if diversity > 0.0 {
+        // Choose from top 'similarity_count' tracks, so that chosen tracks are not too similar to each other
+        chosen.truncate(similarity_count);
+        let mut candidates: Vec<[f32; tree::DIMENSIONS]> = Vec::new();
+        for trk in &chosen {
+            candidates.push(snapshot.metrics(trk.id, &weights)?);
+        }
+        chosen = diversity::rerank(&candidates, metric, diversity, count).into_iter().map(|idx| chosen[idx].clone()).collect();
+    }
+
+    if let Some(shape) = arc {
+        // Choose, and order, from top 'similarity_count' tracks so that energy follows arc
+        chosen.truncate(similarity_count);
+        let mut candidates: Vec<(f32, f32)> = Vec::new();
+        for trk in &chosen {
+            candidates.push((energy::energy(&snapshot.raw_metrics(trk.id)?), trk.sim));
+        }
+        chosen = energy::arrange(shape, &candidates, count).into_iter().map(|idx| chosen[idx].clone()).collect();
+    } else if let Some(temperature) = temperature {
+        // Draw from top 'similarity_count' tracks, favouring the most similar
+        chosen.truncate(similarity_count);
+        chosen = sample(chosen, temperature, &mut rng);
+    } else if shuffle == 1 {
         // Take top 'similarity_count' tracks
         chosen.truncate(similarity_count);
         // Shuffle
-        chosen.shuffle(&mut thread_rng());
+        chosen.shuffle(&mut rng);
     }
 
     // Take 'count' tracks
     chosen.truncate(count);
 
-    let mut resp = String::new();
-    for track in chosen {
-        resp += &track.file;
-        resp += "\n";
-    }
-
     let mut http_resp = HttpResponse::Ok();
     if let Some(di) = debug_info {
         if let Ok(json) = serde_json::to_string(&di) {
@@ -949,32 +1554,54 @@ pub async fn mix(req: HttpRequest, payload: web::Json<MixParams>) -> HttpRespons
             http_resp.set_header("X-Bliss-Debug", json);
         }
     }
-    http_resp.content_type("text/plain; charset=utf-8");
-    http_resp.body(resp)
+    Ok(send_tracks(http_resp, &snapshot.metadata, &chosen, &seed_files, if shuffle == 1 || temperature.is_some() { Some(randomseed) } else { None }, wantjson))
 }
 
-pub async fn list(req: HttpRequest, payload: web::Json<ListParams>) -> impl Responder {
-    let db_path = req.app_data::<web::Data<String>>().unwrap();
-    let all_db_genres = req.app_data::<web::Data<HashSet<String>>>().unwrap();
-    let db = db::Db::new(db_path);
-    let mut count = payload.count.unwrap_or(5) as usize;
-    let filtergenre = payload.filtergenre.unwrap_or(0);
-    let mut filterxmas = payload.filterxmas.unwrap_or(0);
-    let min = payload.min.unwrap_or(0);
-    let max = payload.max.unwrap_or(0);
-    let maxbpmdiff = payload.maxbpmdiff.unwrap_or(0);
+pub async fn list(req: HttpRequest, payload: web::Json<ListParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let snapshot = library.snapshot();
+    if snapshot.is_empty() {
+        return Err(Error::NotLoaded);
+    }
+    let config = req.app_data::<web::Data<config::Settings>>().unwrap().get();
+    let defaults = &config.list;
+    let profile = payload.profile.clone().or(defaults.profile.clone());
+    let weights = library.weights(&profile, &payload.weights)?;
+    let metric = library.metric(&profile, &payload.metric.clone().or(defaults.metric.clone()))?;
+    let blocked = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap().rules();
+    let mut count = payload.count.or(defaults.count).unwrap_or(5) as usize;
+    let filtergenre = payload.filtergenre.or(defaults.filtergenre).unwrap_or(0);
+    let genrepenalty = payload.genrepenalty.or(defaults.genrepenalty).unwrap_or(DEFAULT_GENRE_PENALTY);
+    let mut genre_penalty = genre_penalty(&snapshot, &payload.genremode.clone().or(defaults.genremode.clone()), genrepenalty).await?;
+    let mut tonal = tonal(&snapshot, &payload.tonal.clone().or(defaults.tonal.clone()))?;
+    let mut filterxmas = payload.filterxmas.or(defaults.filterxmas).unwrap_or(0);
+    let min = payload.min.or(defaults.min).unwrap_or(0);
+    let max = payload.max.or(defaults.max).unwrap_or(0);
+    let bpm = BpmFilter {
+        maxdiff: payload.maxbpmdiff.or(defaults.maxbpmdiff).unwrap_or(0),
+        min: payload.minbpm.or(defaults.minbpm).unwrap_or(0),
+        max: payload.maxbpm.or(defaults.maxbpm).unwrap_or(0),
+        halfdouble: payload.halfdouble.or(defaults.halfdouble).unwrap_or(0) == 1,
+    };
+    bpm.validate()?;
+    let levels = LevelFilter {
+        maxloudnessdiff: payload.maxloudnessdiff.or(defaults.maxloudnessdiff).unwrap_or(0.0),
+        maxdynamicsdiff: payload.maxdynamicsdiff.or(defaults.maxdynamicsdiff).unwrap_or(0.0),
+    };
     let track = &payload.track;
     let byartist = payload.byartist;
-    let genregroups = expand_globbed_genres(&payload.genregroups, &all_db_genres);
-    let allgenres = payload.allgenres.unwrap_or(0);
+    let genregroups = resolve_genre_groups(&snapshot, &payload.genregroups)?;
+    let allgenres = payload.allgenres.or(defaults.allgenres).unwrap_or(0);
+    let wantjson = wants_json(&req, &payload.format.clone().or(defaults.format.clone()));
     let mut acceptable_genres: HashSet<String> = HashSet::new();
     let mut all_genres_from_groups: HashSet<String> = HashSet::new();
-    let mut chosen: Vec<String> = Vec::new();
-    let mut filter_out_titles: HashSet<String> = HashSet::new();
+    let mut chosen: Vec<TrackFile> = Vec::new();
+    let mut filter_out_titles: HashSet<Arc<str>> = HashSet::new();
 
     if filterxmas == 1 && chrono::Local::now().month() == 12 {
          filterxmas = 0;
      }
+    let xmas = if filterxmas == 1 { Some(config.xmasgenre.as_str()) } else { None };
 
     if count < MIN_COUNT {
         count = MIN_COUNT;
@@ -983,91 +1610,435 @@ pub async fn list(req: HttpRequest, payload: web::Json<ListParams>) -> impl Resp
     }
 
     log::debug!("Looking for tracks similar to '{}'", track);
-    let seed: Track = get_track(&db, track);
-    if seed.found {
-        if filtergenre == 1 {
-            for group in &genregroups {
-                for genre in group {
-                    all_genres_from_groups.insert(genre.to_string());
-                }
+    let seed: Track = get_track(&snapshot.metadata, track);
+    if !seed.found {
+        return Err(Error::UnknownTrack(track.to_string()));
+    }
+    if filtergenre == 1 {
+        for group in genregroups.iter() {
+            for genre in group {
+                all_genres_from_groups.insert(genre.to_string());
             }
-            if !seed.genres.is_empty() {
-                let genres = get_genres(&genregroups, &seed.genres, allgenres);
-                acceptable_genres.extend(genres);
+        }
+        if !seed.genres.is_empty() {
+            let genres = get_genres(&genregroups, &seed.genres, allgenres);
+            acceptable_genres.extend(genres);
+        }
+    }
+    if let Some(gp) = genre_penalty.as_mut() {
+        gp.seed_genres.extend(seed.genres.iter().cloned());
+    }
+    if let Some(h) = tonal.as_mut() {
+        h.refs.push(seed.id);
+    }
+    let seed_range = SeedRange::of(&seed);
+    filter_out_titles.insert(seed.title);
+    let metrics = snapshot.metrics(seed.id, &weights)?;
+    let mut sim_tracks: Vec<tree::Sim> = Vec::new();
+
+    if byartist == 1 {
+        let vals = snapshot.artist_details(&seed.orig_artist);
+        let tree = tree::Tree::new(&vals.weighted(&weights), metric);
+        sim_tracks.extend(tree.get_similars(&metrics, NonZero::new(config.numsim).unwrap()));
+    } else {
+        sim_tracks.extend(get_tree(&snapshot, &weights, metric).await?.get_similars(&metrics, NonZero::new(config.numsim).unwrap()));
+    }
+
+    let spread = percentile_spread(&sim_tracks, ARTIST_TRACK_SIM_PERCENTILE);
+    for sim_track in rescore(sim_tracks, &HashMap::new(), &snapshot.metadata, &genre_penalty, &tonal, spread) {
+        let mut trk: Track = get_track_from_id(&snapshot.metadata, sim_track.id);
+        trk.sim = sim_track.sim;
+        if let Some(reason) = discard_reason(
+            &trk,
+            &blocked,
+            min,
+            max,
+            &bpm,
+            &levels,
+            Some(&seed_range),
+            (0, 0),
+            0,
+            &acceptable_genres,
+            &all_genres_from_groups,
+            None,
+            None,
+            tonal.as_ref(),
+        ) {
+            log_discard(reason, &trk);
+            continue;
+        }
+        if filter_out_titles.contains(&trk.title) {
+            log("FILTER(title)", &trk);
+            continue;
+        }
+        if let Some(reason) = discard_reason(
+            &trk,
+            &blocked,
+            0,
+            0,
+            &BpmFilter::default(),
+            &LevelFilter::default(),
+            None,
+            (0, 0),
+            if genre_penalty.is_some() { 0 } else { filtergenre },
+            &acceptable_genres,
+            &all_genres_from_groups,
+            xmas,
+            None,
+            tonal.as_ref(),
+        ) {
+            log_discard(reason, &trk);
+            continue;
+        }
+        chosen.push(TrackFile {
+            id: trk.id,
+            file: trk.file,
+            sim: trk.sim,
+            seed: Some(0),
+        });
+        if chosen.len() >= count {
+            break;
+        }
+        filter_out_titles.insert(trk.title);
+    }
+
+    Ok(send_tracks(HttpResponse::Ok(), &snapshot.metadata, &chosen, &[Arc::from(track.as_str())], None, wantjson))
+}
+
+pub async fn path(req: HttpRequest, payload: web::Json<PathParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let snapshot = library.snapshot();
+    if snapshot.is_empty() {
+        return Err(Error::NotLoaded);
+    }
+    let config = req.app_data::<web::Data<config::Settings>>().unwrap().get();
+    // Path shares the filters of mix, and so uses its defaults
+    let defaults = &config.mix;
+    let profile = payload.profile.clone().or(defaults.profile.clone());
+    let weights = library.weights(&profile, &payload.weights)?;
+    let metric = library.metric(&profile, &payload.metric.clone().or(defaults.metric.clone()))?;
+    let tree = get_tree(&snapshot, &weights, metric).await?;
+    let blocked = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap().rules();
+    let mut count = payload.count.or(defaults.count).unwrap_or(5) as usize;
+    let filtergenre = payload.filtergenre.or(defaults.filtergenre).unwrap_or(0);
+    let mut filterxmas = payload.filterxmas.or(defaults.filterxmas).unwrap_or(0);
+    let min = payload.min.or(defaults.min).unwrap_or(0);
+    let max = payload.max.or(defaults.max).unwrap_or(0);
+    let bpm = BpmFilter {
+        maxdiff: payload.maxbpmdiff.or(defaults.maxbpmdiff).unwrap_or(0),
+        min: payload.minbpm.or(defaults.minbpm).unwrap_or(0),
+        max: payload.maxbpm.or(defaults.maxbpm).unwrap_or(0),
+        halfdouble: payload.halfdouble.or(defaults.halfdouble).unwrap_or(0) == 1,
+    };
+    bpm.validate()?;
+    let norepart = payload.norepart.or(defaults.norepart).unwrap_or(0);
+    let norepalb = payload.norepalb.or(defaults.norepalb).unwrap_or(0);
+    let genregroups = resolve_genre_groups(&snapshot, &payload.genregroups)?;
+    let allgenres = payload.allgenres.or(defaults.allgenres).unwrap_or(0);
+    let wantjson = wants_json(&req, &payload.format.clone().or(defaults.format.clone()));
+    let mut filter_out_titles: HashSet<Arc<str>> = HashSet::new();
+    let mut filter_out_artists: HashSet<Arc<str>> = HashSet::new();
+    let mut filter_out_albums: HashSet<Arc<str>> = HashSet::new();
+    let mut filter_out_ids: HashSet<u64> = HashSet::new();
+    let mut acceptable_genres: HashSet<String> = HashSet::new();
+    let mut all_genres_from_groups: HashSet<String> = HashSet::new();
+    let mut chosen_albums: HashSet<Arc<str>> = HashSet::new();
+    let mut chosen: Vec<TrackFile> = Vec::new();
+
+    count = count.clamp(MIN_COUNT, MAX_COUNT);
+
+    if filterxmas == 1 && chrono::Local::now().month() == 12 {
+        filterxmas = 0;
+    }
+    let xmas = if filterxmas == 1 { Some(config.xmasgenre.as_str()) } else { None };
+
+    for group in genregroups.iter() {
+        for genre in group {
+            all_genres_from_groups.insert(genre.to_string());
+        }
+    }
+
+    if let Some(previous) = &payload.previous {
+        let mut pcount = 0;
+        for track in previous {
+            let trk: Track = get_track(&snapshot.metadata, track);
+            if !trk.found {
+                continue;
+            }
+            filter_out_ids.insert(trk.id);
+            if !trk.title.is_empty() {
+                filter_out_titles.insert(trk.title);
+            }
+            if pcount < norepart && !trk.artist.is_empty() {
+                filter_out_artists.insert(trk.artist);
+            }
+            if pcount < norepalb && !trk.album.is_empty() {
+                filter_out_albums.insert(trk.album);
             }
+            pcount += 1;
+        }
+    }
+
+    let start: Track = get_track(&snapshot.metadata, &payload.start);
+    if !start.found {
+        return Err(Error::UnknownTrack(payload.start.to_string()));
+    }
+    let end: Track = get_track(&snapshot.metadata, &payload.end);
+    if !end.found {
+        return Err(Error::UnknownTrack(payload.end.to_string()));
+    }
+
+    // Tracks on the path may be in the genre groups of either end
+    for trk in [&start, &end] {
+        if filtergenre == 1 && !trk.genres.is_empty() {
+            acceptable_genres.extend(get_genres(&genregroups, &trk.genres, allgenres));
+        }
+        filter_out_ids.insert(trk.id);
+        filter_out_titles.insert(trk.title.clone());
+        if norepalb > 0 && !trk.album.is_empty() {
+            filter_out_albums.insert(trk.album.clone());
         }
-        filter_out_titles.insert(seed.title);
-        if let Ok(metrics) = db.get_metrics(seed.id) {
-            let mut sim_tracks: Vec<tree::Sim> = Vec::new();
-
-            if byartist == 1 {
-                let vals = db.load_artist_tree(&seed.orig_artist);
-                let tree = tree::Tree::new(&vals);
-                sim_tracks.extend(tree.get_similars(&metrics, NonZero::new(MIN_NUM_SIM).unwrap()));
+    }
+    let mut seed_range = SeedRange::of(&start);
+    seed_range.extend(&end);
+    let start_metrics = snapshot.metrics(start.id, &weights)?;
+    let end_metrics = snapshot.metrics(end.id, &weights)?;
+
+    log::debug!("Looking for path of {} tracks from '{}' to '{}'", count, start.file, end.file);
+    chosen.push(TrackFile {
+        id: start.id,
+        file: start.file.clone(),
+        sim: 0.0,
+        seed: Some(0),
+    });
+
+    for step in 1..=count {
+        // Point in feature space for this step of the path
+        let pos = step as f32 / (count + 1) as f32;
+        let mut point = [0.0; tree::DIMENSIONS];
+        for (i, val) in point.iter_mut().enumerate() {
+            *val = start_metrics[i] + ((end_metrics[i] - start_metrics[i]) * pos);
+        }
+
+        let mut found: Option<Track> = None;
+        for sim_track in tree.get_similars(&point, NonZero::new(PATH_NUM_SIM).unwrap()) {
+            if filter_out_ids.contains(&sim_track.id) {
+                continue;
+            }
+            let mut trk: Track = get_track_from_id(&snapshot.metadata, sim_track.id);
+            trk.sim = sim_track.sim;
+            if let Some(reason) = discard_reason(
+                &trk,
+                &blocked,
+                min,
+                max,
+                &bpm,
+                &LevelFilter::default(),
+                Some(&seed_range),
+                (0, 0),
+                filtergenre,
+                &acceptable_genres,
+                &all_genres_from_groups,
+                xmas,
+                Some(&chosen_albums),
+                None,
+            ) {
+                log_discard(reason, &trk);
+                continue;
+            }
+            let filter = if norepart > 0 && filter_out_artists.contains(&trk.artist) {
+                Some("FILTER(artist)")
+            } else if !trk.is_various && norepalb > 0 && filter_out_albums.contains(&trk.album) {
+                Some("FILTER(album)")
+            } else if filter_out_titles.contains(&trk.title) {
+                Some("FILTER(title)")
             } else {
-                let tree = req.app_data::<web::Data<tree::Tree>>().unwrap();
-                sim_tracks.extend(tree.get_similars(&metrics, NonZero::new(MIN_NUM_SIM).unwrap()));
+                None
+            };
+            if let Some(reason) = filter {
+                log(reason, &trk);
+                continue;
             }
+            found = Some(trk);
+            break;
+        }
 
-            for sim_track in sim_tracks {
-                let mut trk: Track = get_track_from_id(&db, sim_track.id);
-                trk.sim = sim_track.sim;
-                if let Some(reason) = discard_reason(
-                    &trk,
-                    min,
-                    max,
-                    maxbpmdiff,
-                    None,
-                    Some(seed.bpm),
-                    0,
-                    &acceptable_genres,
-                    &all_genres_from_groups,
-                    0,
-                    None,
-                ) {
-                    log_discard(reason, &trk);
-                    continue;
-                }
-                if filter_out_titles.contains(&trk.title) {
-                    log("FILTER(title)", &trk);
-                    continue;
-                }
-                if let Some(reason) = discard_reason(
-                    &trk,
-                    0,
-                    0,
-                    0,
-                    None,
-                    None,
-                    filtergenre,
-                    &acceptable_genres,
-                    &all_genres_from_groups,
-                    filterxmas,
-                    None,
-                ) {
-                    log_discard(reason, &trk);
-                    continue;
-                }
-                chosen.push(trk.file);
-                if chosen.len() >= count {
-                    break;
-                }
-                filter_out_titles.insert(trk.title);
+        if let Some(trk) = found {
+            log("USABLE", &trk);
+            filter_out_ids.insert(trk.id);
+            filter_out_titles.insert(trk.title.clone());
+            if norepart > 0 {
+                filter_out_artists.insert(trk.artist.clone());
+            }
+            if norepalb > 0 {
+                filter_out_albums.insert(trk.album.clone());
             }
+            chosen_albums.insert(trk.album.clone());
+            chosen.push(TrackFile {
+                id: trk.id,
+                file: trk.file.clone(),
+                sim: trk.sim,
+                seed: None,
+            });
+        } else {
+            log::debug!("No track found for step {}", step);
         }
     }
 
-    let mut resp = String::new();
-    for track in chosen {
-        resp += &track;
-        resp += "\n";
+    chosen.push(TrackFile {
+        id: end.id,
+        file: end.file.clone(),
+        sim: 0.0,
+        seed: Some(1),
+    });
+
+    Ok(send_tracks(HttpResponse::Ok(), &snapshot.metadata, &chosen, &[start.file, end.file], None, wantjson))
+}
+
+pub async fn similar_albums(req: HttpRequest, payload: web::Json<AlbumParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let snapshot = library.snapshot();
+    if snapshot.is_empty() {
+        return Err(Error::NotLoaded);
     }
+    let weights = library.weights(&payload.profile, &payload.weights)?;
+    let count = (payload.count.unwrap_or(5) as usize).clamp(MIN_COUNT, MAX_COUNT);
+    let mintracks = payload.mintracks.unwrap_or(1) as usize;
+    let wantjson = wants_json(&req, &payload.format);
+
+    // Album key is album::albumartist, or album::artist if no album artist
+    let key = if let Some(path) = &payload.path {
+        match snapshot.metadata.find_by_prefix(path) {
+            Some(m) => m.album_key.to_string(),
+            None => { return Err(Error::UnknownAlbum(path.to_string())); }
+        }
+    } else if let (Some(album), Some(artist)) = (&payload.album, &payload.albumartist) {
+        format!("{}::{}", album.to_lowercase(), artist.to_lowercase())
+    } else {
+        return Err(Error::InvalidParams(String::from("Either 'path', or 'album' and 'albumartist', are required")));
+    };
 
-    let mut http_resp = HttpResponse::Ok();
-    http_resp.content_type("text/plain; charset=utf-8");
-    http_resp.body(resp)
+    let album = match snapshot.albums.get(&key) {
+        Some(a) => a,
+        None => { return Err(Error::UnknownAlbum(key)); }
+    };
+    log::debug!("Looking for albums similar to '{}'", album.path);
+    let similars = snapshot.albums.get_similars(album, &weights, mintracks, count);
+    Ok(send_albums(HttpResponse::Ok(), &similars, wantjson))
+}
+
+pub async fn similar_artists(req: HttpRequest, payload: web::Json<ArtistParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let snapshot = library.snapshot();
+    if snapshot.is_empty() {
+        return Err(Error::NotLoaded);
+    }
+    let weights = library.weights(&payload.profile, &payload.weights)?;
+    let count = (payload.count.unwrap_or(5) as usize).clamp(MIN_COUNT, MAX_COUNT);
+    let mintracks = payload.mintracks.unwrap_or(1) as usize;
+    let wantjson = wants_json(&req, &payload.format);
+
+    let artist = match snapshot.artists.get(&payload.artist.to_lowercase()) {
+        Some(a) => a,
+        None => { return Err(Error::UnknownArtist(payload.artist.to_string())); }
+    };
+    log::debug!("Looking for artists similar to '{}'", payload.artist);
+    let similars = snapshot.artists.get_similars(artist, &weights, mintracks, count);
+    Ok(send_artists(HttpResponse::Ok(), &similars, wantjson))
+}
+
+pub async fn get_blocklist(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let blocklist = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap();
+    let entries = blocklist.entries()?;
+    Ok(HttpResponse::Ok().json(BlocklistResponse { entries }))
+}
+
+pub async fn add_to_blocklist(req: HttpRequest, payload: web::Json<blocklist::Entry>) -> Result<HttpResponse, Error> {
+    let blocklist = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap();
+    blocklist.add(&payload)?;
+    Ok(HttpResponse::Ok().body("1"))
+}
+
+pub async fn remove_from_blocklist(req: HttpRequest, payload: web::Json<blocklist::Entry>) -> Result<HttpResponse, Error> {
+    let blocklist = req.app_data::<web::Data<blocklist::Blocklist>>().unwrap();
+    if blocklist.remove(&payload)? {
+        Ok(HttpResponse::Ok().body("1"))
+    } else {
+        Ok(HttpResponse::NotFound().body("0"))
+    }
+}
+
+pub async fn feedback(req: HttpRequest, payload: web::Json<FeedbackParams>) -> Result<HttpResponse, Error> {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    let feedback = req.app_data::<web::Data<feedback::Feedback>>().unwrap();
+    if library.snapshot().metadata.get_rowid(&payload.file) == 0 {
+        return Err(Error::UnknownTrack(payload.file.to_string()));
+    }
+    feedback.record(&payload.file, payload.event)?;
+    Ok(HttpResponse::Ok().body("1"))
 }
 
 pub async fn ready() -> impl Responder {
     "1"
 }
+
+pub async fn reload(req: HttpRequest) -> impl Responder {
+    let library = req.app_data::<web::Data<library::Library>>().unwrap();
+    if library.reload() {
+        HttpResponse::Accepted().body("1")
+    } else {
+        HttpResponse::Conflict().body("0")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracks(sims: &[f32]) -> Vec<TrackFile> {
+        sims.iter().enumerate().map(|(id, sim)| TrackFile {
+            id: id as u64,
+            file: Arc::from(format!("{}.mp3", id)),
+            sim: *sim,
+            seed: None,
+        }).collect()
+    }
+
+    fn ids(tracks: &[TrackFile]) -> Vec<u64> {
+        tracks.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn sample_empty() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(sample(Vec::new(), 1.0, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn sample_zero_temperature() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(ids(&sample(tracks(&[0.3, 0.1, 0.2]), 0.0, &mut rng)), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn sample_low_temperature() {
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(ids(&sample(tracks(&[0.3, 0.1, 0.2, 0.4]), 0.001, &mut rng)), vec![1, 2, 0, 3]);
+    }
+
+    #[test]
+    fn sample_keeps_all_tracks() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut sampled = ids(&sample(tracks(&[0.5, 0.5, 0.5, 0.1, 0.9]), 100.0, &mut rng));
+        sampled.sort();
+        assert_eq!(sampled, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn sample_same_seed() {
+        let sims = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let a = sample(tracks(&sims), 1.0, &mut StdRng::seed_from_u64(42));
+        let b = sample(tracks(&sims), 1.0, &mut StdRng::seed_from_u64(42));
+        assert_eq!(ids(&a), ids(&b));
+    }
+}
@@ -0,0 +1,244 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::error::Error;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Artist,
+    Album,
+    Genre,
+    Glob,
+    File,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: Kind,
+    pub value: String,
+}
+
+// Blocklist entries, in the form used to check tracks. Artists, albums,
+// and genres are lowercase - to match api::Track
+pub struct Rules {
+    artists: HashSet<String>,
+    albums: HashSet<String>,
+    genres: HashSet<String>,
+    files: HashSet<String>,
+    globs: GlobSet,
+}
+
+// Blocklists are stored in the mixer's own database, so that they are kept
+// when the analysis database is updated.
+pub struct Blocklist {
+    conn: Mutex<Connection>,
+    rules: RwLock<Arc<Rules>>,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Artist => "artist",
+            Kind::Album => "album",
+            Kind::Genre => "genre",
+            Kind::Glob => "glob",
+            Kind::File => "file",
+        }
+    }
+
+    fn parse(val: &str) -> Option<Self> {
+        match val {
+            "artist" => Some(Kind::Artist),
+            "album" => Some(Kind::Album),
+            "genre" => Some(Kind::Genre),
+            "glob" => Some(Kind::Glob),
+            "file" => Some(Kind::File),
+            _ => None,
+        }
+    }
+}
+
+impl Entry {
+    // Artists, albums, and genres are matched ignoring case, so store lowercase
+    fn normalised(&self) -> Result<Self, Error> {
+        let value = self.value.trim();
+        if value.is_empty() {
+            return Err(Error::InvalidParams(String::from("Blocklist value is empty")));
+        }
+        let value = match self.kind {
+            Kind::Artist | Kind::Album | Kind::Genre => value.to_lowercase(),
+            Kind::Glob => {
+                Glob::new(value).map_err(|e| Error::InvalidParams(e.to_string()))?;
+                value.to_string()
+            }
+            Kind::File => value.to_string(),
+        };
+        Ok(Self { kind: self.kind, value })
+    }
+}
+
+impl Rules {
+    fn new(entries: &[Entry]) -> Self {
+        let mut rules = Self {
+            artists: HashSet::new(),
+            albums: HashSet::new(),
+            genres: HashSet::new(),
+            files: HashSet::new(),
+            globs: GlobSet::empty(),
+        };
+        let mut globs = GlobSetBuilder::new();
+        for entry in entries {
+            match entry.kind {
+                Kind::Artist => { rules.artists.insert(entry.value.clone()); }
+                Kind::Album => { rules.albums.insert(entry.value.clone()); }
+                Kind::Genre => { rules.genres.insert(entry.value.clone()); }
+                Kind::File => { rules.files.insert(entry.value.clone()); }
+                Kind::Glob => match Glob::new(&entry.value) {
+                    Ok(glob) => { globs.add(glob); }
+                    Err(e) => { log::error!("Invalid blocklist glob '{}'. {}", entry.value, e); }
+                },
+            }
+        }
+        match globs.build() {
+            Ok(set) => { rules.globs = set; }
+            Err(e) => { log::error!("Failed to build blocklist globs. {}", e); }
+        }
+        rules
+    }
+
+    // Check if track is blocked. Album is album::albumartist (or album::artist),
+    // so the album artist is also checked against blocked artists.
+    pub fn is_blocked(&self, file: &str, artist: &str, album: &str, genres: &HashSet<String>) -> bool {
+        if self.files.contains(file) || self.artists.contains(artist) || self.albums.contains(album) {
+            return true;
+        }
+        if !self.artists.is_empty() {
+            if let Some((_, album_artist)) = album.rsplit_once("::") {
+                if self.artists.contains(album_artist) {
+                    return true;
+                }
+            }
+        }
+        if !self.genres.is_empty() && !self.genres.is_disjoint(genres) {
+            return true;
+        }
+        !self.globs.is_empty() && self.globs.is_match(file)
+    }
+}
+
+impl Blocklist {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(Error::Unavailable)?;
+        // Blocklist, and feedback, each have their own connection to this DB
+        conn.busy_timeout(db::BUSY_TIMEOUT).map_err(Error::Unavailable)?;
+        conn.execute("CREATE TABLE IF NOT EXISTS Blocklist (Kind TEXT NOT NULL, Value TEXT NOT NULL, PRIMARY KEY(Kind, Value));", [])?;
+        let blocklist = Self {
+            conn: Mutex::new(conn),
+            rules: RwLock::new(Arc::new(Rules::new(&[]))),
+        };
+        blocklist.update_rules()?;
+        Ok(blocklist)
+    }
+
+    pub fn rules(&self) -> Arc<Rules> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT Kind, Value FROM Blocklist ORDER BY Kind, Value;")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (kind, value) = row?;
+            match Kind::parse(&kind) {
+                Some(kind) => entries.push(Entry { kind, value }),
+                None => log::error!("Unknown blocklist type '{}'", kind),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn add(&self, entry: &Entry) -> Result<(), Error> {
+        let entry = entry.normalised()?;
+        self.conn.lock().unwrap().execute("INSERT OR IGNORE INTO Blocklist (Kind, Value) VALUES (?, ?);", params![entry.kind.as_str(), entry.value])?;
+        self.update_rules()
+    }
+
+    // Returns false if entry was not in blocklist
+    pub fn remove(&self, entry: &Entry) -> Result<bool, Error> {
+        let entry = entry.normalised()?;
+        let removed = self.conn.lock().unwrap().execute("DELETE FROM Blocklist WHERE Kind=? AND Value=?;", params![entry.kind.as_str(), entry.value])?;
+        if removed > 0 {
+            self.update_rules()?;
+        }
+        Ok(removed > 0)
+    }
+
+    fn update_rules(&self) -> Result<(), Error> {
+        let rules = Arc::new(Rules::new(&self.entries()?));
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(kind: Kind, value: &str) -> Entry {
+        Entry { kind, value: value.to_string() }
+    }
+
+    fn genres(vals: &[&str]) -> HashSet<String> {
+        vals.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn rules() {
+        let blocklist = Blocklist::open(":memory:").unwrap();
+        blocklist.add(&entry(Kind::Artist, " The Artist ")).unwrap();
+        blocklist.add(&entry(Kind::Album, "Album::Other")).unwrap();
+        blocklist.add(&entry(Kind::Genre, "Comedy")).unwrap();
+        blocklist.add(&entry(Kind::File, "Music/a.mp3")).unwrap();
+        blocklist.add(&entry(Kind::Glob, "Audiobooks/**")).unwrap();
+        let rules = blocklist.rules();
+        let none = genres(&[]);
+        assert!(rules.is_blocked("b.mp3", "the artist", "x::y", &none));
+        assert!(rules.is_blocked("b.mp3", "x", "album::other", &none));
+        assert!(rules.is_blocked("b.mp3", "x", "other album::the artist", &none));
+        assert!(rules.is_blocked("b.mp3", "x", "x::y", &genres(&["rock", "comedy"])));
+        assert!(rules.is_blocked("Music/a.mp3", "x", "x::y", &none));
+        assert!(rules.is_blocked("Audiobooks/x/1.mp3", "x", "x::y", &none));
+        assert!(!rules.is_blocked("music/a.mp3", "x", "x::y", &genres(&["rock"])));
+        assert!(!rules.is_blocked("b.mp3", "x", "album::x", &none));
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let blocklist = Blocklist::open(":memory:").unwrap();
+        assert!(blocklist.add(&entry(Kind::Artist, " ")).is_err());
+        assert!(blocklist.add(&entry(Kind::Glob, "a/[")).is_err());
+        blocklist.add(&entry(Kind::Artist, "Artist")).unwrap();
+        blocklist.add(&entry(Kind::Artist, "artist")).unwrap();
+        let entries = blocklist.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].value, "artist");
+        assert!(!blocklist.remove(&entry(Kind::Album, "artist")).unwrap());
+        assert!(blocklist.remove(&entry(Kind::Artist, "ARTIST")).unwrap());
+        assert!(blocklist.entries().unwrap().is_empty());
+        assert!(!blocklist.rules().is_blocked("a.mp3", "artist", "x::y", &genres(&[])));
+    }
+}
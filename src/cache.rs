@@ -0,0 +1,192 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::tree;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::time::UNIX_EPOCH;
+
+// Increase if the format of cached data changes
+const VERSION: u32 = 2;
+
+// Identifies the DB, and weights, used to build cached tree. If any of these
+// change then the cache is rebuilt.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Key {
+    version: u32,
+    dimensions: usize,
+    db_modified: (u64, u32), // Seconds, and nanoseconds, since epoch
+    db_size: u64,
+    weights: Vec<u32>,
+}
+
+impl Key {
+    // Create key for current state of DB. Should be called before DB is read, so
+    // that changes made whilst reading cause a rebuild on next load.
+    pub fn new(db_path: &str, weights: &[u32]) -> Option<Self> {
+        let meta = fs::metadata(db_path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            version: VERSION,
+            dimensions: tree::DIMENSIONS,
+            db_modified: (modified.as_secs(), modified.subsec_nanos()),
+            db_size: meta.len(),
+            weights: weights.to_vec(),
+        })
+    }
+}
+
+// Cache is stored next to the DB
+pub fn path(db_path: &str) -> String {
+    format!("{}.tree", db_path)
+}
+
+// Load unweighted analysis results, and tree, if cached using same key
+pub fn load(cache_path: &str, key: &Key) -> Option<(tree::AnalysisDetails, tree::Tree)> {
+    let file = fs::File::open(cache_path).ok()?;
+    // Nothing read can be larger than the file, so limit allocations to this in case it is corrupt
+    let options = bincode::DefaultOptions::new().with_limit(file.metadata().ok()?.len());
+    let mut reader = BufReader::new(file);
+    match options.deserialize_from::<_, Key>(&mut reader) {
+        Ok(cached) if cached == *key => { }
+        Ok(_) => {
+            log::debug!("{} is out of date", cache_path);
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Failed to read {}. {}", cache_path, e);
+            return None;
+        }
+    }
+    let loaded = options.deserialize_from::<_, tree::AnalysisDetails>(&mut reader)
+        .and_then(|raw| Ok((raw, options.deserialize_from::<_, tree::Tree>(&mut reader)?)));
+    match loaded {
+        Ok((raw, tree)) if raw.values.len() == raw.ids.len() && tree.is_valid(raw.ids.len()) => {
+            log::debug!("Loaded tree from {}", cache_path);
+            Some((raw, tree))
+        }
+        Ok(_) => {
+            log::warn!("Failed to read {}. Inconsistent number of tracks", cache_path);
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to read {}. {}", cache_path, e);
+            None
+        }
+    }
+}
+
+// Save unweighted analysis results, and tree. Written to a temporary file first,
+// so that an interrupted write does not leave a partial cache.
+pub fn save(cache_path: &str, key: &Key, raw: &tree::AnalysisDetails, tree: &tree::Tree) {
+    let tmp_path = format!("{}.tmp", cache_path);
+    let saved = fs::File::create(&tmp_path).map_err(|e| e.to_string()).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        let options = bincode::DefaultOptions::new();
+        options.serialize_into(&mut writer, key)
+            .and_then(|_| options.serialize_into(&mut writer, raw))
+            .and_then(|_| options.serialize_into(&mut writer, tree))
+            .map_err(|e| e.to_string())?;
+        writer.into_inner().map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, cache_path).map_err(|e| e.to_string())
+    });
+    match saved {
+        Ok(_) => { log::debug!("Saved tree to {}", cache_path); }
+        Err(e) => {
+            log::warn!("Failed to save {}. {}", cache_path, e);
+            let _ = fs::remove_file(&tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{point, temp_path};
+
+    fn details() -> tree::AnalysisDetails {
+        tree::AnalysisDetails {
+            values: vec![point(&[0.0]), point(&[1.0]), point(&[2.0])],
+            ids: vec![10, 20, 30],
+        }
+    }
+
+    #[test]
+    fn key() {
+        let db_path = temp_path("key.db");
+        fs::write(&db_path, "abc").unwrap();
+        let key = Key::new(&db_path, &[1, 2]).unwrap();
+        assert_eq!(Key::new(&db_path, &[1, 2]).unwrap(), key);
+        assert_ne!(Key::new(&db_path, &[1, 3]).unwrap(), key);
+        fs::write(&db_path, "abcd").unwrap();
+        assert_ne!(Key::new(&db_path, &[1, 2]).unwrap(), key);
+        fs::remove_file(&db_path).unwrap();
+        assert!(Key::new(&db_path, &[1, 2]).is_none());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let db_path = temp_path("load.db");
+        let cache_path = path(&db_path);
+        fs::write(&db_path, "abc").unwrap();
+        let key = Key::new(&db_path, &[1]).unwrap();
+        let raw = details();
+        save(&cache_path, &key, &raw, &tree::Tree::new(&raw, tree::Metric::Euclidean));
+        let (loaded, tree) = load(&cache_path, &key).unwrap();
+        assert_eq!(loaded.ids, raw.ids);
+        assert_eq!(loaded.values, raw.values);
+        assert!(tree.is_valid(raw.ids.len()));
+        assert!(load(&cache_path, &Key::new(&db_path, &[2]).unwrap()).is_none());
+        fs::remove_file(&cache_path).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn version_change() {
+        let db_path = temp_path("version.db");
+        let cache_path = path(&db_path);
+        fs::write(&db_path, "abc").unwrap();
+        let key = Key::new(&db_path, &[1]).unwrap();
+        let old_key = Key { version: VERSION - 1, ..Key::new(&db_path, &[1]).unwrap() };
+        let raw = details();
+        save(&cache_path, &old_key, &raw, &tree::Tree::new(&raw, tree::Metric::Euclidean));
+        assert!(load(&cache_path, &old_key).is_some());
+        assert!(load(&cache_path, &key).is_none());
+        fs::remove_file(&cache_path).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn invalid_cache() {
+        let db_path = temp_path("invalid.db");
+        let cache_path = path(&db_path);
+        fs::write(&db_path, "abc").unwrap();
+        let key = Key::new(&db_path, &[1]).unwrap();
+        assert!(load(&cache_path, &key).is_none());
+
+        // Tree does not match analysis results
+        let raw = details();
+        let other = tree::AnalysisDetails { values: vec![point(&[0.0])], ids: vec![10] };
+        save(&cache_path, &key, &raw, &tree::Tree::new(&other, tree::Metric::Euclidean));
+        assert!(load(&cache_path, &key).is_none());
+
+        // Truncated
+        save(&cache_path, &key, &raw, &tree::Tree::new(&raw, tree::Metric::Euclidean));
+        let data = fs::read(&cache_path).unwrap();
+        fs::write(&cache_path, &data[..data.len() / 2]).unwrap();
+        assert!(load(&cache_path, &key).is_none());
+
+        // Corrupt
+        fs::write(&cache_path, vec![0xff; 64]).unwrap();
+        assert!(load(&cache_path, &key).is_none());
+        fs::remove_file(&cache_path).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::error::Error;
+use crate::weights;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+// Defaults for api::MixParams, used when a field is not in the request
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MixDefaults {
+    pub count: Option<u16>,
+    pub filtergenre: Option<u16>,
+    pub filterxmas: Option<u16>,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub maxbpmdiff: Option<i16>,
+    pub minbpm: Option<i16>,
+    pub maxbpm: Option<i16>,
+    pub halfdouble: Option<u16>,
+    pub maxloudnessdiff: Option<f32>,
+    pub maxdynamicsdiff: Option<f32>,
+    pub minyear: Option<u16>,
+    pub maxyear: Option<u16>,
+    pub maxyeardiff: Option<u16>,
+    pub shuffle: Option<u16>,
+    pub temperature: Option<f32>,
+    pub norepart: Option<u16>,
+    pub norepalb: Option<u16>,
+    pub allgenres: Option<u16>,
+    pub forest: Option<u16>,
+    pub adaptiveweights: Option<u16>,
+    pub arc: Option<String>,
+    pub diversity: Option<f32>,
+    pub tonal: Option<String>,
+    pub tonalref: Option<String>,
+    pub genremode: Option<String>,
+    pub genrepenalty: Option<f32>,
+    pub format: Option<String>,
+    pub profile: Option<String>,
+    pub metric: Option<String>,
+}
+
+// Defaults for api::ListParams, used when a field is not in the request
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListDefaults {
+    pub count: Option<u16>,
+    pub filtergenre: Option<u16>,
+    pub filterxmas: Option<u16>,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+    pub maxbpmdiff: Option<i16>,
+    pub minbpm: Option<i16>,
+    pub maxbpm: Option<i16>,
+    pub halfdouble: Option<u16>,
+    pub maxloudnessdiff: Option<f32>,
+    pub maxdynamicsdiff: Option<f32>,
+    pub allgenres: Option<u16>,
+    pub genremode: Option<String>,
+    pub genrepenalty: Option<f32>,
+    pub tonal: Option<String>,
+    pub format: Option<String>,
+    pub profile: Option<String>,
+    pub metric: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Server settings. These are only read at startup, and are overridden by command-line options
+    pub db: Option<String>,
+    pub port: Option<u16>,
+    pub address: Option<String>,
+    pub logging: Option<String>,
+    pub lms: Option<String>,
+    pub upload: Option<bool>,
+    pub weights: Option<String>,
+    pub watch: Option<u64>,
+    pub state: Option<String>,
+    pub cache: Option<bool>,
+
+    // Mix settings. These are re-read on SIGHUP
+    pub profiles: HashMap<String, weights::ProfileEntry>,
+    pub genregroups: HashMap<String, Vec<Vec<String>>>,
+    pub mix: MixDefaults,
+    pub list: ListDefaults,
+    pub numsim: usize, // Min number of similar tracks to get from tree
+    pub maxartisttracks: usize, // Max number of tracks per artist to pick random track from when shuffling
+    pub xmasgenre: String,
+}
+
+pub struct Settings {
+    path: Option<String>,
+    config: RwLock<Arc<Config>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db: None,
+            port: None,
+            address: None,
+            logging: None,
+            lms: None,
+            upload: None,
+            weights: None,
+            watch: None,
+            state: None,
+            cache: None,
+            profiles: HashMap::new(),
+            genregroups: HashMap::new(),
+            mix: MixDefaults::default(),
+            list: ListDefaults::default(),
+            numsim: 5000,
+            maxartisttracks: 5,
+            xmasgenre: String::from("christmas"),
+        }
+    }
+}
+
+// Load config from TOML, or JSON, file - depending upon file extension
+pub fn load(path: &str) -> Result<Config, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::InvalidConfig(format!("Failed to read {}. {}", path, e)))?;
+    let mut config: Config = if path.to_lowercase().ends_with(".toml") {
+        toml::from_str(&contents).map_err(|e| Error::InvalidConfig(format!("Failed to parse {}. {}", path, e)))?
+    } else {
+        serde_json::from_str(&contents).map_err(|e| Error::InvalidConfig(format!("Failed to parse {}. {}", path, e)))?
+    };
+    if config.numsim == 0 {
+        return Err(Error::InvalidConfig(String::from("numsim must be greater than 0")));
+    }
+    config.xmasgenre = config.xmasgenre.to_lowercase();
+    Ok(config)
+}
+
+// Get config file path from command-line. This is needed before the command-line
+// is parsed, so that options in the config file can be overridden.
+pub fn path_from_args(args: &[String]) -> Option<String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-c" || arg == "--config" {
+            return iter.next().cloned();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+impl Settings {
+    pub fn new(path: Option<String>, config: Config) -> Self {
+        Self {
+            path,
+            config: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Re-read config file, without replacing the current config. This allows the caller to
+    // validate anything derived from the config before calling replace.
+    pub fn load(&self) -> Result<Arc<Config>, Error> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => { return Ok(self.get()); }
+        };
+        log::info!("Reloading {}", path);
+        Ok(Arc::new(load(path)?))
+    }
+
+    pub fn replace(&self, config: Arc<Config>) {
+        *self.config.write().unwrap() = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::temp_path;
+
+    fn load_str(name: &str, contents: &str) -> Result<Config, Error> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let config = load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn load_toml() {
+        let config = load_str("config.toml", r#"
+            port = 12001
+            numsim = 100
+            xmasgenre = "Xmas"
+
+            [mix]
+            count = 10
+            genremode = "soft"
+
+            [profiles]
+            quiet = "0.5"
+        "#).unwrap();
+        assert_eq!(config.port, Some(12001));
+        assert_eq!(config.numsim, 100);
+        assert_eq!(config.xmasgenre, "xmas");
+        assert_eq!(config.mix.count, Some(10));
+        assert_eq!(config.mix.genremode.as_deref(), Some("soft"));
+        assert!(config.profiles.contains_key("quiet"));
+        // Anything not set uses default
+        assert_eq!(config.maxartisttracks, 5);
+        assert!(config.db.is_none());
+        assert!(config.mix.shuffle.is_none());
+    }
+
+    #[test]
+    fn load_json() {
+        let config = load_str("config.json", r#"{ "db": "music.db", "list": { "count": 20 } }"#).unwrap();
+        assert_eq!(config.db.as_deref(), Some("music.db"));
+        assert_eq!(config.list.count, Some(20));
+        assert_eq!(config.numsim, 5000);
+        assert_eq!(config.xmasgenre, "christmas");
+    }
+
+    #[test]
+    fn load_invalid() {
+        assert!(load(&temp_path("missing.toml")).is_err());
+        assert!(load_str("syntax.toml", "numsim = ").is_err());
+        assert!(load_str("numsim.toml", "numsim = 0").is_err());
+        assert!(load_str("unknown.toml", "unknown = 1").is_err());
+        assert!(load_str("unknown_mix.json", r#"{ "mix": { "unknown": 1 } }"#).is_err());
+    }
+
+    #[test]
+    fn args() {
+        let args = |vals: &[&str]| path_from_args(&vals.iter().map(|v| v.to_string()).collect::<Vec<String>>());
+        assert_eq!(args(&["bliss-mixer", "-c", "a.toml"]), Some(String::from("a.toml")));
+        assert_eq!(args(&["bliss-mixer", "-p", "1", "--config", "b.json"]), Some(String::from("b.json")));
+        assert_eq!(args(&["bliss-mixer", "--config=c.toml"]), Some(String::from("c.toml")));
+        assert_eq!(args(&["bliss-mixer", "-c"]), None);
+        assert_eq!(args(&["bliss-mixer", "-p", "1"]), None);
+    }
+
+    #[test]
+    fn reload() {
+        let path = temp_path("reload.toml");
+        fs::write(&path, "numsim = 100").unwrap();
+        let settings = Settings::new(Some(path.clone()), load(&path).unwrap());
+        fs::write(&path, "numsim = 200").unwrap();
+        // Loading does not replace current config
+        let config = settings.load().unwrap();
+        assert_eq!(config.numsim, 200);
+        assert_eq!(settings.get().numsim, 100);
+        settings.replace(config);
+        assert_eq!(settings.get().numsim, 200);
+        fs::write(&path, "numsim = 0").unwrap();
+        assert!(settings.load().is_err());
+        assert_eq!(settings.get().numsim, 200);
+        fs::remove_file(&path).unwrap();
+
+        let settings = Settings::new(None, Config::default());
+        assert_eq!(settings.load().unwrap().numsim, 5000);
+    }
+}
@@ -6,11 +6,15 @@
  *
  **/
 
+use crate::error::Error;
 use crate::tree;
-use rusqlite::Connection;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags};
 use std::collections::HashSet;
+use std::time::Duration;
 
-pub static mut WEIGHTS: [f32;tree::DIMENSIONS] = [1.0;tree::DIMENSIONS];
+// How long to wait for another connection (e.g. analyser) to release a lock on a DB
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Metadata {
     pub file: String,
@@ -21,46 +25,33 @@ pub struct Metadata {
     pub genre: Option<String>,
     pub duration: Option<u32>,
     pub tempo: Option<f32>,
+    pub loudness: Option<f32>,
+    pub dynamics: Option<f32>,
+    pub year: Option<u16>,
 }
 
 pub struct Db {
     pub conn: Connection,
 }
 
-pub fn init_weights(weights_str: &String) {
-    let vals = weights_str.split(",");
-    let mut pos = 0;
-    unsafe {
-        for val in vals {
-            if pos<tree::DIMENSIONS {
-                WEIGHTS[pos] = val.parse::<f32>().unwrap();
-            }
-            pos+=1;
-        }
-        log::debug!("Weights: {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}", 
-            WEIGHTS[0], WEIGHTS[1], WEIGHTS[2], WEIGHTS[3], WEIGHTS[4],
-            WEIGHTS[5], WEIGHTS[6], WEIGHTS[7], WEIGHTS[8], WEIGHTS[9],
-            WEIGHTS[10], WEIGHTS[11], WEIGHTS[12], WEIGHTS[13], WEIGHTS[14],
-            WEIGHTS[15], WEIGHTS[16], WEIGHTS[17], WEIGHTS[18], WEIGHTS[19],
-            WEIGHTS[20], WEIGHTS[21], WEIGHTS[22]);
-    }
-}
-
-fn adjust(vals: [f32;tree::DIMENSIONS]) -> [f32;tree::DIMENSIONS] {
-    let mut adjusted: [f32;tree::DIMENSIONS] = [0.0;tree::DIMENSIONS];
-    unsafe {
-        for (i, x) in vals.iter().enumerate() {
-            adjusted[i] = x * WEIGHTS[i];
-        }
-    }
-    adjusted
+// Year may be stored as a number, or as text starting with the year
+fn parse_year(val: Value) -> Option<u16> {
+    let year = match val {
+        Value::Integer(i) if (0..=i64::from(u16::MAX)).contains(&i) => Some(i as u16),
+        Value::Real(r) => Some(r as u16),
+        Value::Text(t) => t.trim().get(0..4).and_then(|y| y.parse::<u16>().ok()),
+        _ => None,
+    };
+    year.filter(|y| *y > 0)
 }
 
 impl Db {
-    pub fn new(path: &String) -> Self {
-        Self {
-            conn: Connection::open(path).unwrap(),
-        }
+    pub fn new(path: &str) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Self {
+            conn,
+        })
     }
 
     pub fn close(self) {
@@ -69,297 +60,171 @@ impl Db {
         }
     }
 
-    pub fn load(&self) -> tree::AnalysisDetails {
+    pub fn load(&self) -> Result<tree::AnalysisDetails, Error> {
         log::debug!("Load tree");
-        let mut details = tree::AnalysisDetails::new();
-        match self.conn.prepare("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13, rowid FROM TracksV2 WHERE Ignore IS NOT 1") {
-            Ok(mut stmt) => {
-                let track_iter = stmt.query_map([], |row| {
-                    Ok((row.get(0)?,
-                        row.get(1)?,
-                        row.get(2)?,
-                        row.get(3)?,
-                        row.get(4)?,
-                        row.get(5)?,
-                        row.get(6)?,
-                        row.get(7)?,
-                        row.get(8)?,
-                        row.get(9)?,
-                        row.get(10)?,
-                        row.get(11)?,
-                        row.get(12)?,
-                        row.get(13)?,
-                        row.get(14)?,
-                        row.get(15)?,
-                        row.get(16)?,
-                        row.get(17)?,
-                        row.get(18)?,
-                        row.get(19)?,
-                        row.get(20)?,
-                        row.get(21)?,
-                        row.get(22)?,
-                        row.get(23)?
-                    ))
-                }).unwrap();
-                let mut num_loaded = 0;
-                for tr in track_iter {
-                    let track = tr.unwrap();
-                    let vals:[f32;tree::DIMENSIONS] = [
-                                track.0,
-                                track.1,
-                                track.2,
-                                track.3,
-                                track.4,
-                                track.5,
-                                track.6,
-                                track.7,
-                                track.8,
-                                track.9,
-                                track.10,
-                                track.11,
-                                track.12,
-                                track.13,
-                                track.14,
-                                track.15,
-                                track.16,
-                                track.17,
-                                track.18,
-                                track.19,
-                                track.20,
-                                track.21,
-                                track.22];
-                    num_loaded += 1;
-                    details.values.push(adjust(vals));
-                    details.ids.push(track.23);
-                }
-                log::debug!("Tree loaded {} track(s)", num_loaded);
-            }
-            Err(e) => { log::error!("Failed to load tree from DB. {}", e); }
-        }
-        details
+        self.load_analysis("Ignore IS NOT 1")
     }
 
-    pub fn load_artist_tree(&self, artist: &str) -> tree::AnalysisDetails {
-        log::debug!("Load artist '{}' tree", artist);
-        let mut details = tree::AnalysisDetails::new();
-        match self.conn.prepare("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13, rowid FROM TracksV2 WHERE Artist=:artist;") {
-            Ok(mut stmt) => {
-                let track_iter = stmt.query_map(&[(":artist", &artist)], |row| {
-                    Ok((row.get(0)?,
-                        row.get(1)?,
-                        row.get(2)?,
-                        row.get(3)?,
-                        row.get(4)?,
-                        row.get(5)?,
-                        row.get(6)?,
-                        row.get(7)?,
-                        row.get(8)?,
-                        row.get(9)?,
-                        row.get(10)?,
-                        row.get(11)?,
-                        row.get(12)?,
-                        row.get(13)?,
-                        row.get(14)?,
-                        row.get(15)?,
-                        row.get(16)?,
-                        row.get(17)?,
-                        row.get(18)?,
-                        row.get(19)?,
-                        row.get(20)?,
-                        row.get(21)?,
-                        row.get(22)?,
-                        row.get(23)?
-                    ))
-                }).unwrap();
-                let mut num_loaded = 0;
-                for tr in track_iter {
-                    let track = tr.unwrap();
-                    let vals:[f32;tree::DIMENSIONS] = [
-                                track.0,
-                                track.1,
-                                track.2,
-                                track.3,
-                                track.4,
-                                track.5,
-                                track.6,
-                                track.7,
-                                track.8,
-                                track.9,
-                                track.10,
-                                track.11,
-                                track.12,
-                                track.13,
-                                track.14,
-                                track.15,
-                                track.16,
-                                track.17,
-                                track.18,
-                                track.19,
-                                track.20,
-                                track.21,
-                                track.22];
-                    num_loaded += 1;
-                    details.values.push(adjust(vals));
-                    details.ids.push(track.23);
-                }
-                log::debug!("Tree loaded {} track(s)", num_loaded);
-            }
-            Err(e) => { log::error!("Failed to load tree from DB. {}", e); }
-        }
-        details
+    // Ignored tracks are not used in mixes, but may still be used as seeds
+    pub fn load_ignored(&self) -> Result<tree::AnalysisDetails, Error> {
+        log::debug!("Load ignored tracks");
+        self.load_analysis("Ignore IS 1")
     }
 
-    pub fn get_rowid(&self, path: &str) -> u64 {
-        let mut id: u64 = 0;
-        if let Ok(mut stmt) = self.conn.prepare("SELECT rowid FROM TracksV2 WHERE File=:path;") {
-            if let Ok(val) = stmt.query_row(&[(":path", &path)], |row| row.get(0)) {
-                id = val;
-            }
+    fn load_analysis(&self, condition: &str) -> Result<tree::AnalysisDetails, Error> {
+        let mut details = tree::AnalysisDetails::new();
+        let mut stmt = self.conn.prepare(&format!("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13, rowid FROM TracksV2 WHERE {}", condition))?;
+        let track_iter = stmt.query_map([], |row| {
+            Ok((row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?,
+                row.get(8)?,
+                row.get(9)?,
+                row.get(10)?,
+                row.get(11)?,
+                row.get(12)?,
+                row.get(13)?,
+                row.get(14)?,
+                row.get(15)?,
+                row.get(16)?,
+                row.get(17)?,
+                row.get(18)?,
+                row.get(19)?,
+                row.get(20)?,
+                row.get(21)?,
+                row.get(22)?,
+                row.get(23)?
+            ))
+        })?;
+        let mut num_loaded = 0;
+        for tr in track_iter {
+            let track = match tr {
+                Ok(track) => track,
+                Err(e) => {
+                    log::error!("Failed to read track analysis. {}", e);
+                    continue;
+                }
+            };
+            let vals:[f32;tree::DIMENSIONS] = [
+                        track.0,
+                        track.1,
+                        track.2,
+                        track.3,
+                        track.4,
+                        track.5,
+                        track.6,
+                        track.7,
+                        track.8,
+                        track.9,
+                        track.10,
+                        track.11,
+                        track.12,
+                        track.13,
+                        track.14,
+                        track.15,
+                        track.16,
+                        track.17,
+                        track.18,
+                        track.19,
+                        track.20,
+                        track.21,
+                        track.22];
+            num_loaded += 1;
+            details.values.push(vals);
+            details.ids.push(track.23);
         }
-        id
+        log::debug!("Loaded {} track(s)", num_loaded);
+        Ok(details)
     }
 
-    pub fn get_all_genres(&self) -> HashSet<String> {
+    pub fn get_all_genres(&self) -> Result<HashSet<String>, Error> {
         log::debug!("getting genres from db.");
         let mut all_available_genres = HashSet::new();
 
-        match self.conn.prepare("SELECT DISTINCT Genre FROM TracksV2 WHERE ignore IS NOT 1;") {
-            Ok(mut stmt) => match stmt.query_map([], |row| Ok(row.get::<_, Option<String>>(0)?)) {
-                Ok(column) => {
-                    for item in column {
-                        let item_content = item.unwrap().unwrap();
-                        let item_genres: Vec<&str> = item_content.split(";").collect();
-                        for genre in item_genres {
-                            let trimmed_genre = genre.trim();
-                            if !trimmed_genre.is_empty() {
-                                all_available_genres.insert(String::from(trimmed_genre));
-                            }
-                        }
-                    }
+        let mut stmt = self.conn.prepare("SELECT DISTINCT Genre FROM TracksV2 WHERE ignore IS NOT 1;")?;
+        let column = stmt.query_map([], |row| Ok(row.get::<_, Option<String>>(0)?))?;
+        for item_content in column.flatten().flatten() {
+            let item_genres: Vec<&str> = item_content.split(";").collect();
+            for genre in item_genres {
+                let trimmed_genre = genre.trim();
+                if !trimmed_genre.is_empty() {
+                    all_available_genres.insert(String::from(trimmed_genre));
                 }
-                Err(e) => { log::debug!("Failed to read all genres: {}", e); }
             }
-            Err(e) => { log::debug!("Failed to read all genres: {}", e); }
         }
-        all_available_genres
-    }
-
-    pub fn get_metadata(&self, id: u64) -> Result<Metadata, rusqlite::Error> {
-        let mut stmt = self.conn.prepare("SELECT File, Title, Artist, AlbumArtist, Album, Genre, Duration, Tempo FROM TracksV2 WHERE rowid=:rowid;")?;
-        let row = stmt.query_row(&[(":rowid", &id)], |row| {
-                Ok(Metadata {
-                    file: row.get(0)?,
-                    title: row.get(1)?,
-                    artist: row.get(2)?,
-                    album_artist: row.get(3)?,
-                    album: row.get(4)?,
-                    genre: row.get(5)?,
-                    duration: row.get(6)?,
-                    tempo: row.get(7)?,
-                })
-            }).unwrap();
-        Ok(row)
-    }
-
-    pub fn get_metrics(&self, id: u64) -> Result<[f32; tree::DIMENSIONS], rusqlite::Error> {
-        let mut stmt = self.conn.prepare("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13 FROM TracksV2 WHERE rowid=:rowid;").unwrap();
-        let row = stmt.query_row(&[(":rowid", &id)], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                    row.get(6)?,
-                    row.get(7)?,
-                    row.get(8)?,
-                    row.get(9)?,
-                    row.get(10)?,
-                    row.get(11)?,
-                    row.get(12)?,
-                    row.get(13)?,
-                    row.get(14)?,
-                    row.get(15)?,
-                    row.get(16)?,
-                    row.get(17)?,
-                    row.get(18)?,
-                    row.get(19)?,
-                    row.get(20)?,
-                    row.get(21)?,
-                    row.get(22)?,
-                ))
-            }).unwrap();
-        let metrics: [f32; tree::DIMENSIONS] = [
-            row.0, row.1, row.2, row.3, row.4, row.5, row.6, row.7, row.8, row.9, row.10, row.11,
-            row.12, row.13, row.14, row.15, row.16, row.17, row.18, row.19, row.20, row.21, row.22
-        ];
-        Ok(adjust(metrics))
+        Ok(all_available_genres)
     }
 
-    pub fn get_raw_metrics(&self, id: u64) -> Result<[f32; tree::DIMENSIONS], rusqlite::Error> {
-        let mut stmt = self.conn.prepare("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13 FROM TracksV2 WHERE rowid=:rowid;").unwrap();
-        let row = stmt.query_row(&[(":rowid", &id)], |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                    row.get(6)?,
-                    row.get(7)?,
-                    row.get(8)?,
-                    row.get(9)?,
-                    row.get(10)?,
-                    row.get(11)?,
-                    row.get(12)?,
-                    row.get(13)?,
-                    row.get(14)?,
-                    row.get(15)?,
-                    row.get(16)?,
-                    row.get(17)?,
-                    row.get(18)?,
-                    row.get(19)?,
-                    row.get(20)?,
-                    row.get(21)?,
-                    row.get(22)?,
-                ))
-            }).unwrap();
-        let metrics: [f32; tree::DIMENSIONS] = [
-            row.0, row.1, row.2, row.3, row.4, row.5, row.6, row.7, row.8, row.9, row.10, row.11,
-            row.12, row.13, row.14, row.15, row.16, row.17, row.18, row.19, row.20, row.21, row.22
-        ];
-        Ok(metrics)
+    // Names (lowercase) of columns in TracksV2, used to detect optional columns
+    fn get_columns(&self) -> HashSet<String> {
+        let mut columns = HashSet::new();
+        match self.conn.prepare("PRAGMA table_info(TracksV2);") {
+            Ok(mut stmt) => match stmt.query_map([], |row| row.get::<_, String>(1)) {
+                Ok(names) => {
+                    for name in names.flatten() {
+                        columns.insert(name.to_lowercase());
+                    }
+                }
+                Err(e) => { log::debug!("Failed to read columns: {}", e); }
+            }
+            Err(e) => { log::debug!("Failed to read columns: {}", e); }
+        }
+        columns
     }
 
-    pub fn get_all_raw_metrics(&self) -> Vec<(u64, [f32; tree::DIMENSIONS])> {
-        let mut results: Vec<(u64, [f32; tree::DIMENSIONS])> = Vec::new();
-        match self.conn.prepare("SELECT Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, Chroma11, Chroma12, Chroma13, rowid FROM TracksV2 WHERE ignore IS NOT 1;") {
-            Ok(mut stmt) => {
-                let track_iter = stmt.query_map([], |row| {
-                    Ok((
-                        row.get(0)?,  row.get(1)?,  row.get(2)?,  row.get(3)?,
-                        row.get(4)?,  row.get(5)?,  row.get(6)?,  row.get(7)?,
-                        row.get(8)?,  row.get(9)?,  row.get(10)?, row.get(11)?,
-                        row.get(12)?, row.get(13)?, row.get(14)?, row.get(15)?,
-                        row.get(16)?, row.get(17)?, row.get(18)?, row.get(19)?,
-                        row.get(20)?, row.get(21)?, row.get(22)?, row.get::<_, u64>(23)?,
-                    ))
-                }).unwrap();
-                for tr in track_iter {
-                    let t = tr.unwrap();
-                    let metrics: [f32; tree::DIMENSIONS] = [
-                        t.0,  t.1,  t.2,  t.3,  t.4,  t.5,  t.6,  t.7,
-                        t.8,  t.9,  t.10, t.11, t.12, t.13, t.14, t.15,
-                        t.16, t.17, t.18, t.19, t.20, t.21, t.22,
-                    ];
-                    results.push((t.23, metrics));
-                }
+    pub fn get_all_metadata(&self) -> Result<Vec<(u64, Metadata)>, Error> {
+        let mut results: Vec<(u64, Metadata)> = Vec::new();
+        // Year is optional, and may be stored as either Year or Date (e.g. "1977-05-25")
+        let columns = self.get_columns();
+        let year_column = if columns.contains("year") { "Year" } else if columns.contains("date") { "Date" } else { "NULL" };
+        log::debug!("Reading year from {}", year_column);
+        let mut stmt = self.conn.prepare(&format!("SELECT rowid, File, Title, Artist, AlbumArtist, Album, Genre, Duration, Tempo, MeanLoudness, StdDevLoudness, {} FROM TracksV2;", year_column))?;
+        let track_iter = stmt.query_map([], |row| {
+            Ok((row.get::<_, u64>(0)?, Metadata {
+                file: row.get(1)?,
+                title: row.get(2)?,
+                artist: row.get(3)?,
+                album_artist: row.get(4)?,
+                album: row.get(5)?,
+                genre: row.get(6)?,
+                duration: row.get(7)?,
+                tempo: row.get(8)?,
+                loudness: row.get(9)?,
+                dynamics: row.get(10)?,
+                year: parse_year(row.get::<_, Value>(11)?),
+            }))
+        })?;
+        for tr in track_iter {
+            match tr {
+                Ok(t) => { results.push(t); }
+                Err(e) => { log::error!("Failed to read metadata. {}", e); }
             }
-            Err(e) => { log::error!("Failed to load all raw metrics: {}", e); }
         }
-        log::debug!("Loaded {} raw metrics for full scan", results.len());
-        results
+        log::debug!("Loaded metadata for {} track(s)", results.len());
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn year() {
+        assert_eq!(parse_year(Value::Integer(1999)), Some(1999));
+        assert_eq!(parse_year(Value::Real(2001.0)), Some(2001));
+        assert_eq!(parse_year(Value::Text(String::from("1987"))), Some(1987));
+        assert_eq!(parse_year(Value::Text(String::from(" 1987-05-12"))), Some(1987));
+        assert_eq!(parse_year(Value::Integer(0)), None);
+        assert_eq!(parse_year(Value::Integer(-5)), None);
+        assert_eq!(parse_year(Value::Integer(100000)), None);
+        assert_eq!(parse_year(Value::Text(String::from("87"))), None);
+        assert_eq!(parse_year(Value::Text(String::from("unknown"))), None);
+        assert_eq!(parse_year(Value::Null), None);
     }
 }
@@ -0,0 +1,96 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::tree;
+
+// Choose 'count' tracks using maximal marginal relevance. Candidates are the (weighted)
+// metrics of tracks, sorted closest to the seeds first. Each pick trades off closeness
+// to the seeds (position in candidates) against distance from tracks already picked,
+// with diversity of 0 only using the former and 1 only the latter. Returns candidate
+// indexes, in the order they were picked.
+pub fn rerank(candidates: &[[f32; tree::DIMENSIONS]], metric: tree::Metric, diversity: f32, count: usize) -> Vec<usize> {
+    let num = candidates.len();
+    let mut picked: Vec<usize> = Vec::new();
+    if num == 0 || count == 0 {
+        return picked;
+    }
+
+    // Distance from each candidate to the closest picked track
+    let mut closest = vec![f32::MAX; num];
+    let mut used = vec![false; num];
+    let mut max_dist: f32 = 0.0;
+    let mut next = 0; // Closest to seeds is always picked first
+    while picked.len() < count.min(num) {
+        used[next] = true;
+        picked.push(next);
+        for (idx, metrics) in candidates.iter().enumerate() {
+            if !used[idx] {
+                let dist = metric.distance(metrics, &candidates[next]);
+                closest[idx] = closest[idx].min(dist);
+                max_dist = max_dist.max(dist);
+            }
+        }
+
+        let mut best: Option<(usize, f32)> = None;
+        for idx in 0..num {
+            if used[idx] {
+                continue;
+            }
+            let relevance = 1.0 - (idx as f32 / num as f32);
+            let novelty = if max_dist > 0.0 { closest[idx] / max_dist } else { 0.0 };
+            let score = ((1.0 - diversity) * relevance) + (diversity * novelty);
+            if best.is_none_or(|(_, s)| score > s) {
+                best = Some((idx, score));
+            }
+        }
+        match best {
+            Some((idx, _)) => { next = idx; }
+            None => { break; }
+        }
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::point;
+
+    #[test]
+    fn empty() {
+        assert!(rerank(&[], tree::Metric::Euclidean, 0.5, 5).is_empty());
+        assert!(rerank(&[point(&[0.0]), point(&[1.0])], tree::Metric::Euclidean, 0.5, 0).is_empty());
+    }
+
+    #[test]
+    fn no_diversity_keeps_order() {
+        let candidates = [point(&[0.0]), point(&[0.1]), point(&[5.0]), point(&[0.2])];
+        assert_eq!(rerank(&candidates, tree::Metric::Euclidean, 0.0, 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn full_diversity_picks_furthest() {
+        let candidates = [point(&[0.0]), point(&[0.1]), point(&[0.2]), point(&[5.0])];
+        assert_eq!(rerank(&candidates, tree::Metric::Euclidean, 1.0, 2), vec![0, 3]);
+    }
+
+    #[test]
+    fn count_larger_than_candidates() {
+        let candidates = [point(&[0.0]), point(&[1.0]), point(&[2.0])];
+        let mut picked = rerank(&candidates, tree::Metric::Manhattan, 0.5, 10);
+        assert_eq!(picked[0], 0);
+        picked.sort();
+        assert_eq!(picked, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn identical_candidates() {
+        let candidates = [point(&[1.0]), point(&[1.0]), point(&[1.0])];
+        assert_eq!(rerank(&candidates, tree::Metric::Cosine, 1.0, 3), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,149 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::tree;
+use bliss_audio::AnalysisIndex;
+
+// How much a candidate's distance from the seeds counts against it, relative
+// to how far its energy is from that wanted at its position.
+const SIM_WEIGHT: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Rising,
+    Falling,
+    Peak,
+}
+
+impl Shape {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val.to_lowercase().as_str() {
+            "rising" => Some(Shape::Rising),
+            "falling" => Some(Shape::Falling),
+            "peak" => Some(Shape::Peak),
+            _ => None,
+        }
+    }
+
+    // Wanted energy, from 0 (lowest of candidates) to 1 (highest), at position in list
+    fn target(&self, pos: usize, num: usize) -> f32 {
+        let t = if num > 1 { pos as f32 / (num - 1) as f32 } else { 0.5 };
+        match self {
+            Shape::Rising => t,
+            Shape::Falling => 1.0 - t,
+            Shape::Peak => 1.0 - ((2.0 * t) - 1.0).abs(),
+        }
+    }
+}
+
+// Energy of a track, from its unweighted tempo and loudness
+pub fn energy(metrics: &[f32; tree::DIMENSIONS]) -> f32 {
+    (metrics[AnalysisIndex::Tempo as usize] + metrics[AnalysisIndex::MeanLoudness as usize]) / 2.0
+}
+
+// Choose 'count' tracks from candidates, given as (energy, sim), so that their energy
+// follows shape - preferring those closest to the seeds. Returns candidate indexes, in
+// the order they should be played.
+pub fn arrange(shape: Shape, candidates: &[(f32, f32)], count: usize) -> Vec<usize> {
+    let num = count.min(candidates.len());
+    if num == 0 {
+        return Vec::new();
+    }
+    let (min_energy, max_energy) = candidates.iter().fold((f32::MAX, f32::MIN), |(lo, hi), (e, _)| (lo.min(*e), hi.max(*e)));
+    let (min_sim, max_sim) = candidates.iter().fold((f32::MAX, f32::MIN), |(lo, hi), (_, s)| (lo.min(*s), hi.max(*s)));
+    let energy_range = if max_energy > min_energy { max_energy - min_energy } else { 1.0 };
+    let sim_range = if max_sim > min_sim { max_sim - min_sim } else { 1.0 };
+
+    // Pick candidate for each position, closest in energy to target
+    let mut used = vec![false; candidates.len()];
+    let mut picked: Vec<usize> = Vec::new();
+    for pos in 0..num {
+        let target = shape.target(pos, num);
+        let mut best: Option<(usize, f32)> = None;
+        for (idx, (e, s)) in candidates.iter().enumerate() {
+            if used[idx] {
+                continue;
+            }
+            let cost = (((e - min_energy) / energy_range) - target).abs() + (SIM_WEIGHT * (s - min_sim) / sim_range);
+            if best.is_none_or(|(_, c)| cost < c) {
+                best = Some((idx, cost));
+            }
+        }
+        if let Some((idx, _)) = best {
+            used[idx] = true;
+            picked.push(idx);
+        }
+    }
+
+    // Order picked tracks so that energy follows shape exactly
+    picked.sort_by(|a, b| candidates[*a].0.total_cmp(&candidates[*b].0));
+    match shape {
+        Shape::Rising => picked,
+        Shape::Falling => picked.into_iter().rev().collect(),
+        Shape::Peak => {
+            let mut before: Vec<usize> = Vec::new();
+            let mut after: Vec<usize> = Vec::new();
+            for (i, idx) in picked.into_iter().enumerate() {
+                if i % 2 == 0 { before.push(idx); } else { after.push(idx); }
+            }
+            before.extend(after.into_iter().rev());
+            before
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Candidates with increasing energy, all equally close to seeds
+    fn candidates() -> Vec<(f32, f32)> {
+        vec![(0.3, 0.0), (0.0, 0.0), (0.4, 0.0), (0.1, 0.0), (0.2, 0.0)]
+    }
+
+    fn energies(candidates: &[(f32, f32)], picked: &[usize]) -> Vec<f32> {
+        picked.iter().map(|idx| candidates[*idx].0).collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert!(arrange(Shape::Rising, &[], 5).is_empty());
+        assert!(arrange(Shape::Peak, &candidates(), 0).is_empty());
+    }
+
+    #[test]
+    fn rising() {
+        let c = candidates();
+        assert_eq!(energies(&c, &arrange(Shape::Rising, &c, 5)), vec![0.0, 0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn falling() {
+        let c = candidates();
+        assert_eq!(energies(&c, &arrange(Shape::Falling, &c, 3)), vec![0.4, 0.2, 0.0]);
+    }
+
+    #[test]
+    fn peak() {
+        let c = candidates();
+        assert_eq!(energies(&c, &arrange(Shape::Peak, &c, 5)), vec![0.0, 0.2, 0.4, 0.3, 0.1]);
+    }
+
+    #[test]
+    fn prefers_closest() {
+        // Same energy, so closest to seeds should be picked
+        let c = vec![(0.5, 0.9), (0.5, 0.1), (0.5, 0.5)];
+        assert_eq!(arrange(Shape::Rising, &c, 1), vec![1]);
+    }
+
+    #[test]
+    fn count_larger_than_candidates() {
+        let c = candidates();
+        assert_eq!(arrange(Shape::Rising, &c, 10).len(), c.len());
+    }
+}
@@ -0,0 +1,109 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidGenre(String),
+    InvalidWeights(String),
+    UnknownProfile(String),
+    UnknownGenreGroups(String),
+    InvalidParams(String),
+    InvalidConfig(String),
+    UnknownTrack(String),
+    UnknownAlbum(String),
+    UnknownArtist(String),
+    NotLoaded,
+    Unavailable(rusqlite::Error),
+    Database(rusqlite::Error),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: &'static str,
+    message: String,
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidGenre(_) => "invalid_genre",
+            Error::InvalidWeights(_) => "invalid_weights",
+            Error::UnknownProfile(_) => "unknown_profile",
+            Error::UnknownGenreGroups(_) => "unknown_genre_groups",
+            Error::InvalidParams(_) => "invalid_params",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::UnknownTrack(_) => "unknown_track",
+            Error::UnknownAlbum(_) => "unknown_album",
+            Error::UnknownArtist(_) => "unknown_artist",
+            Error::NotLoaded => "not_loaded",
+            Error::Unavailable(_) => "db_unavailable",
+            Error::Database(_) => "db_error",
+            Error::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidGenre(e) => write!(f, "Invalid genre pattern. {}", e),
+            Error::InvalidWeights(e) => write!(f, "Invalid weights. {}", e),
+            Error::UnknownProfile(p) => write!(f, "Unknown weights profile '{}'", p),
+            Error::UnknownGenreGroups(g) => write!(f, "Unknown genre groups '{}'", g),
+            Error::InvalidParams(e) => write!(f, "Invalid parameters. {}", e),
+            Error::InvalidConfig(e) => write!(f, "Invalid config. {}", e),
+            Error::UnknownTrack(t) => write!(f, "Track '{}' not found in DB", t),
+            Error::UnknownAlbum(a) => write!(f, "Album '{}' not found in DB", a),
+            Error::UnknownArtist(a) => write!(f, "Artist '{}' not found in DB", a),
+            Error::NotLoaded => write!(f, "No analysis results loaded"),
+            Error::Unavailable(e) => write!(f, "Failed to open database. {}", e),
+            Error::Database(e) => write!(f, "Database error. {}", e),
+            Error::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::InvalidGenre(_) | Error::InvalidWeights(_) | Error::UnknownProfile(_) | Error::UnknownGenreGroups(_)
+            | Error::InvalidParams(_) => StatusCode::BAD_REQUEST,
+            Error::UnknownTrack(_) | Error::UnknownAlbum(_) | Error::UnknownArtist(_) => StatusCode::NOT_FOUND,
+            Error::NotLoaded | Error::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Database(_) | Error::InvalidConfig(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log::error!("{}", self);
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            code: self.code(),
+            message: self.to_string(),
+        })
+    }
+}
+
+// Malformed, or wrongly typed, request bodies are reported as invalid parameters
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    Error::InvalidParams(err.to_string()).into()
+}
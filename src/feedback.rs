@@ -0,0 +1,210 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::error::Error;
+use crate::library;
+use crate::tree;
+use crate::weights;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::num::NonZero;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+// Each skip increases a track's distance by 25%, up to 4 skips
+const SKIP_PENALTY: f32 = 0.25;
+const MAX_SKIPS: u32 = 4;
+// Each dislike increases a track's distance by 200%
+const DISLIKE_PENALTY: f32 = 2.0;
+// Each like decreases a track's distance by ~10%, up to 3 likes
+const LIKE_BOOST: f32 = 0.1;
+const MAX_LIKES: u32 = 3;
+// Close neighbours of skipped, or disliked, tracks get half of the penalty
+const NEIGHBOUR_SCALE: f32 = 0.5;
+const NUM_NEIGHBOURS: usize = 5;
+const MAX_NEIGHBOUR_SIM: f32 = 0.05;
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Skip,
+    Like,
+    Dislike,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    skips: u32,
+    likes: u32,
+    dislikes: u32,
+}
+
+// Factors to multiply a track's distance by, calculated for a snapshot
+struct Factors {
+    snapshot: Weak<library::Snapshot>,
+    factors: Arc<HashMap<u64, f32>>,
+}
+
+// Feedback is stored in the mixer's own database, alongside blocklists.
+pub struct Feedback {
+    conn: Mutex<Connection>,
+    counts: Mutex<HashMap<String, Counts>>,
+    generation: AtomicU64, // Incremented whenever counts change
+    factors: Mutex<Option<Factors>>,
+}
+
+impl Counts {
+    fn factor(&self) -> f32 {
+        let penalty = (1.0 + (SKIP_PENALTY * self.skips.min(MAX_SKIPS) as f32)) * (1.0 + (DISLIKE_PENALTY * self.dislikes as f32));
+        penalty / (1.0 + (LIKE_BOOST * self.likes.min(MAX_LIKES) as f32))
+    }
+}
+
+impl Feedback {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(Error::Unavailable)?;
+        // Blocklist, and feedback, each have their own connection to this DB
+        conn.busy_timeout(db::BUSY_TIMEOUT).map_err(Error::Unavailable)?;
+        conn.execute("CREATE TABLE IF NOT EXISTS Feedback (File TEXT PRIMARY KEY, Skips INTEGER NOT NULL, Likes INTEGER NOT NULL, Dislikes INTEGER NOT NULL);", [])?;
+        let mut counts = HashMap::new();
+        {
+            let mut stmt = conn.prepare("SELECT File, Skips, Likes, Dislikes FROM Feedback;")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, Counts { skips: row.get(1)?, likes: row.get(2)?, dislikes: row.get(3)? })))?;
+            for row in rows {
+                let (file, c) = row?;
+                counts.insert(file, c);
+            }
+        }
+        log::debug!("Loaded feedback for {} track(s)", counts.len());
+        Ok(Self {
+            conn: Mutex::new(conn),
+            counts: Mutex::new(counts),
+            generation: AtomicU64::new(0),
+            factors: Mutex::new(None),
+        })
+    }
+
+    // Liking a track clears its dislikes, and disliking clears its likes.
+    pub fn record(&self, file: &str, event: Event) -> Result<(), Error> {
+        {
+            let mut counts = self.counts.lock().unwrap();
+            let mut c = counts.get(file).copied().unwrap_or_default();
+            match event {
+                Event::Skip => { c.skips += 1; }
+                Event::Like => { c.likes += 1; c.dislikes = 0; }
+                Event::Dislike => { c.dislikes += 1; c.likes = 0; }
+            }
+            self.conn.lock().unwrap().execute("INSERT OR REPLACE INTO Feedback (File, Skips, Likes, Dislikes) VALUES (?, ?, ?, ?);",
+                                              params![file, c.skips, c.likes, c.dislikes])?;
+            counts.insert(file.to_string(), c);
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+        *self.factors.lock().unwrap() = None;
+        Ok(())
+    }
+
+    // Get map of track ID to factor to multiply its distance by. Neighbours are found using the
+    // default weights, and the result is kept until either feedback or the snapshot changes.
+    pub fn factors(&self, snapshot: &Arc<library::Snapshot>, weights: &weights::Weights) -> Arc<HashMap<u64, f32>> {
+        if let Some(f) = self.factors.lock().unwrap().as_ref() {
+            if Weak::ptr_eq(&f.snapshot, &Arc::downgrade(snapshot)) {
+                return f.factors.clone();
+            }
+        }
+
+        // Copy counts, so that locks are not held whilst looking up neighbours
+        let (generation, counts) = {
+            let counts = self.counts.lock().unwrap();
+            (self.generation.load(Ordering::SeqCst), counts.clone())
+        };
+        let mut factors: HashMap<u64, f32> = HashMap::new();
+        if !counts.is_empty() {
+            let tree = snapshot.tree(weights, tree::Metric::default());
+            let mut penalised: Vec<(u64, usize, f32)> = Vec::new();
+            for (file, c) in counts.iter() {
+                let id = snapshot.metadata.get_rowid(file);
+                if let Some(pos) = snapshot.position(id) {
+                    let factor = c.factor();
+                    factors.insert(id, factor);
+                    if c.skips > 0 || c.dislikes > 0 {
+                        penalised.push((id, pos, factor));
+                    }
+                }
+            }
+            let mut neighbours: HashMap<u64, f32> = HashMap::new();
+            for (id, pos, factor) in penalised {
+                let neighbour_factor = 1.0 + ((factor - 1.0) * NEIGHBOUR_SCALE);
+                let metrics = weights::apply(&snapshot.raw.values[pos], weights);
+                for sim in tree.get_similars(&metrics, NonZero::new(NUM_NEIGHBOURS + 1).unwrap()) {
+                    if sim.id != id && sim.sim <= MAX_NEIGHBOUR_SIM {
+                        let entry = neighbours.entry(sim.id).or_insert(1.0);
+                        *entry = (*entry).max(neighbour_factor);
+                    }
+                }
+            }
+            // Feedback for a track itself takes precedence over that of its neighbours
+            for (id, factor) in neighbours {
+                factors.entry(id).or_insert(factor);
+            }
+            log::debug!("Feedback affects {} track(s)", factors.len());
+        }
+
+        let factors = Arc::new(factors);
+        let mut cached = self.factors.lock().unwrap();
+        // Feedback may have changed whilst calculating, in which case these are already out of date
+        if self.generation.load(Ordering::SeqCst) == generation {
+            *cached = Some(Factors { snapshot: Arc::downgrade(snapshot), factors: factors.clone() });
+        }
+        factors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::temp_path;
+
+    fn counts(skips: u32, likes: u32, dislikes: u32) -> Counts {
+        Counts { skips, likes, dislikes }
+    }
+
+    #[test]
+    fn factor() {
+        assert_eq!(counts(0, 0, 0).factor(), 1.0);
+        assert_eq!(counts(1, 0, 0).factor(), 1.25);
+        assert_eq!(counts(10, 0, 0).factor(), 2.0);
+        assert_eq!(counts(0, 0, 1).factor(), 3.0);
+        assert!((counts(0, 10, 0).factor() - (1.0 / 1.3)).abs() < 1e-6);
+        assert!(counts(2, 1, 0).factor() > 1.0);
+    }
+
+    #[test]
+    fn record() {
+        let path = temp_path("feedback.db");
+        {
+            let feedback = Feedback::open(&path).unwrap();
+            feedback.record("a.mp3", Event::Skip).unwrap();
+            feedback.record("a.mp3", Event::Dislike).unwrap();
+            feedback.record("a.mp3", Event::Dislike).unwrap();
+            feedback.record("b.mp3", Event::Dislike).unwrap();
+            feedback.record("b.mp3", Event::Like).unwrap();
+        }
+        // Feedback is kept when re-opened
+        let feedback = Feedback::open(&path).unwrap();
+        let counts = feedback.counts.lock().unwrap();
+        let a = counts["a.mp3"];
+        assert_eq!((a.skips, a.likes, a.dislikes), (1, 0, 2));
+        let b = counts["b.mp3"];
+        assert_eq!((b.skips, b.likes, b.dislikes), (0, 1, 0));
+        drop(counts);
+        drop(feedback);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
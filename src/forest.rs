@@ -6,6 +6,7 @@
  *
  **/
 
+use crate::error::Error;
 use crate::tree;
 use extended_isolation_forest;
 use noisy_float::prelude::*;
@@ -23,7 +24,8 @@ struct ScoredTrack {
     track: Track,
 }
 
-pub fn sort_by_closest(details: &tree::AnalysisDetails, seeds: &Vec<Track>) -> Vec<Track> {
+// Sort tracks by their anomaly score, lowest (i.e. most like seeds) first
+pub fn sort_by_closest(details: &tree::AnalysisDetails, seeds: &Vec<Track>) -> Result<Vec<tree::Sim>, Error> {
     let opts = extended_isolation_forest::ForestOptions {
         n_trees: 1000,
         sample_size: seeds.len().min(256),
@@ -31,7 +33,8 @@ pub fn sort_by_closest(details: &tree::AnalysisDetails, seeds: &Vec<Track>) -> V
         extension_level: 10,
     };
     let seed_array = &*seeds.iter().map(|s| s.metrics).collect::<Vec<_>>();
-    let forest = extended_isolation_forest::Forest::from_slice(seed_array, &opts).unwrap();
+    let forest = extended_isolation_forest::Forest::from_slice(seed_array, &opts)
+        .map_err(|e| Error::Internal(format!("Failed to create forest. {:?}", e)))?;
 
     // Prepare tracks
     let tracks: Vec<Track> = details
@@ -57,5 +60,5 @@ pub fn sort_by_closest(details: &tree::AnalysisDetails, seeds: &Vec<Track>) -> V
     scored_tracks.par_sort_unstable_by_key(|scored| scored.score);
 
     // Return tracks sorted by score
-    scored_tracks.into_iter().map(|scored| scored.track).collect()
+    Ok(scored_tracks.into_iter().map(|scored| tree::Sim { id: scored.track.id, sim: scored.score.raw() }).collect())
 }
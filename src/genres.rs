@@ -0,0 +1,188 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::metadata;
+use crate::tree;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy)]
+pub enum Source {
+    Cooccurrence, // How often genres are used together on the same track
+    Centroid, // How close the average analysis features of the genres' tracks are
+}
+
+// Affinity, from 0 (unrelated) to 1 (same), between each pair of genres in DB
+pub struct Affinity {
+    index: HashMap<String, usize>,
+    cooccurrence: Vec<f32>,
+    centroid: Vec<f32>,
+}
+
+impl Source {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val.to_lowercase().as_str() {
+            "cooccurrence" => Some(Source::Cooccurrence),
+            "centroid" => Some(Source::Centroid),
+            _ => None,
+        }
+    }
+}
+
+impl Affinity {
+    pub fn new() -> Self {
+        Self {
+            index: HashMap::new(),
+            cooccurrence: Vec::new(),
+            centroid: Vec::new(),
+        }
+    }
+
+    pub fn build(details: &tree::AnalysisDetails, metadata: &metadata::MetadataIndex) -> Self {
+        let mut affinity = Self::new();
+        for id in &details.ids {
+            if let Some(m) = metadata.get(*id) {
+                for genre in m.genres.iter() {
+                    if !affinity.index.contains_key(genre) {
+                        affinity.index.insert(genre.clone(), affinity.index.len());
+                    }
+                }
+            }
+        }
+        let num = affinity.index.len();
+        if num == 0 {
+            return affinity;
+        }
+
+        let mut counts = vec![0.0f64; num];
+        let mut pairs = vec![0.0f64; num * num];
+        let mut sums = vec![[0.0f64; tree::DIMENSIONS]; num];
+        for (id, vals) in details.ids.iter().zip(details.values.iter()) {
+            let m = match metadata.get(*id) {
+                Some(m) => m,
+                None => { continue; }
+            };
+            let positions: Vec<usize> = m.genres.iter().map(|g| affinity.index[g]).collect();
+            for a in &positions {
+                counts[*a] += 1.0;
+                for (i, val) in vals.iter().enumerate() {
+                    sums[*a][i] += *val as f64;
+                }
+                for b in &positions {
+                    pairs[(a * num) + b] += 1.0;
+                }
+            }
+        }
+
+        // Co-occurrence: number of tracks with both genres, relative to the number with each genre
+        affinity.cooccurrence = vec![0.0; num * num];
+        for a in 0..num {
+            for b in 0..num {
+                let pos = (a * num) + b;
+                affinity.cooccurrence[pos] = (pairs[pos] / (counts[a] * counts[b]).sqrt()) as f32;
+            }
+        }
+
+        // Centroid: distance between genres' average features, relative to the largest distance
+        let centroids: Vec<Vec<f64>> = sums.iter().zip(counts.iter()).map(|(s, c)| s.iter().map(|v| v / c).collect()).collect();
+        let mut distances = vec![0.0f64; num * num];
+        let mut max_dist: f64 = 0.0;
+        for a in 0..num {
+            for b in (a + 1)..num {
+                let dist: f64 = centroids[a].iter().zip(centroids[b].iter()).map(|(x, y)| (x - y) * (x - y)).sum();
+                distances[(a * num) + b] = dist;
+                distances[(b * num) + a] = dist;
+                max_dist = max_dist.max(dist);
+            }
+        }
+        affinity.centroid = distances.iter().map(|d| if max_dist > 0.0 { (1.0 - (d / max_dist)) as f32 } else { 1.0 }).collect();
+        log::debug!("Genre affinity contains {} genre(s)", num);
+        affinity
+    }
+
+    pub fn get(&self, source: Source, a: &str, b: &str) -> f32 {
+        if a == b {
+            return 1.0;
+        }
+        match (self.index.get(a), self.index.get(b)) {
+            (Some(pa), Some(pb)) => {
+                let pos = (pa * self.index.len()) + pb;
+                match source {
+                    Source::Cooccurrence => self.cooccurrence[pos],
+                    Source::Centroid => self.centroid[pos],
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    // Highest affinity between any genre in 'genres', and any in 'other'. If either
+    // has no genres then they are considered to be the same.
+    pub fn between(&self, source: Source, genres: &HashSet<String>, other: &HashSet<String>) -> f32 {
+        if genres.is_empty() || other.is_empty() {
+            return 1.0;
+        }
+        let mut best: f32 = 0.0;
+        for a in genres {
+            for b in other {
+                best = best.max(self.get(source, a, b));
+                if best >= 1.0 {
+                    return best;
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genres(vals: &[&str]) -> HashSet<String> {
+        vals.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn affinity() -> Affinity {
+        Affinity {
+            index: HashMap::from([(String::from("rock"), 0), (String::from("pop"), 1)]),
+            cooccurrence: vec![1.0, 0.25, 0.25, 1.0],
+            centroid: vec![1.0, 0.5, 0.5, 1.0],
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches!(Source::parse("CoOccurrence"), Some(Source::Cooccurrence)));
+        assert!(matches!(Source::parse("centroid"), Some(Source::Centroid)));
+        assert!(Source::parse("other").is_none());
+    }
+
+    #[test]
+    fn get() {
+        let affinity = affinity();
+        assert_eq!(affinity.get(Source::Cooccurrence, "rock", "pop"), 0.25);
+        assert_eq!(affinity.get(Source::Centroid, "pop", "rock"), 0.5);
+        assert_eq!(affinity.get(Source::Centroid, "jazz", "jazz"), 1.0);
+        assert_eq!(affinity.get(Source::Centroid, "rock", "jazz"), 0.0);
+    }
+
+    #[test]
+    fn between() {
+        let affinity = affinity();
+        assert_eq!(affinity.between(Source::Cooccurrence, &genres(&["rock"]), &genres(&["pop", "jazz"])), 0.25);
+        assert_eq!(affinity.between(Source::Cooccurrence, &genres(&["rock", "jazz"]), &genres(&["jazz"])), 1.0);
+        assert_eq!(affinity.between(Source::Centroid, &genres(&["rock"]), &genres(&[])), 1.0);
+        assert_eq!(affinity.between(Source::Centroid, &genres(&["jazz"]), &genres(&["blues"])), 0.0);
+    }
+
+    #[test]
+    fn build_empty() {
+        let affinity = Affinity::build(&tree::AnalysisDetails::new(), &metadata::MetadataIndex::new());
+        assert_eq!(affinity.get(Source::Cooccurrence, "rock", "pop"), 0.0);
+    }
+}
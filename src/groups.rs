@@ -0,0 +1,212 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::metadata;
+use crate::tree;
+use crate::weights;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Distribution of the analysis features of a group of tracks, e.g. an album.
+pub struct Group {
+    pub key: Arc<str>,
+    pub name: Option<Arc<str>>,
+    pub artist: Option<Arc<str>>,
+    pub path: String, // Common path of group's tracks
+    pub num_tracks: usize,
+    mean: [f32; tree::DIMENSIONS],
+    stddev: [f32; tree::DIMENSIONS],
+}
+
+pub struct GroupIndex {
+    groups: Vec<Group>,
+    keys: HashMap<Arc<str>, usize>,
+}
+
+pub struct Similar<'a> {
+    pub group: &'a Group,
+    pub sim: f32,
+}
+
+struct Accumulator {
+    name: Option<Arc<str>>,
+    artist: Option<Arc<str>>,
+    path: String,
+    count: usize,
+    sum: [f64; tree::DIMENSIONS],
+    sum_sq: [f64; tree::DIMENSIONS],
+}
+
+fn common_path(a: &str, b: &str) -> String {
+    let mut len = 0;
+    for ((pos, ca), cb) in a.char_indices().zip(b.chars()) {
+        if ca != cb {
+            break;
+        }
+        if ca == '/' {
+            len = pos + 1;
+        }
+    }
+    a[..len].to_string()
+}
+
+fn dir_of(path: &str) -> String {
+    match path.rfind('/') {
+        Some(pos) => path[..pos + 1].to_string(),
+        None => String::new(),
+    }
+}
+
+impl GroupIndex {
+    pub fn new() -> Self {
+        Self {
+            groups: Vec::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    // Build index from (unweighted) analysis results. 'group_of' returns the key, name, and artist
+    // of the group a track belongs to - or None if the track should not be used.
+    pub fn build<F>(details: &tree::AnalysisDetails, metadata: &metadata::MetadataIndex, group_of: F) -> Self
+    where
+        F: Fn(&metadata::TrackMeta) -> Option<(Arc<str>, Option<Arc<str>>, Option<Arc<str>>)>,
+    {
+        let mut accumulators: HashMap<Arc<str>, Accumulator> = HashMap::new();
+        for (id, vals) in details.ids.iter().zip(details.values.iter()) {
+            let meta = match metadata.get(*id) {
+                Some(m) => m,
+                None => { continue; }
+            };
+            let (key, name, artist) = match group_of(meta) {
+                Some(g) => g,
+                None => { continue; }
+            };
+            let acc = accumulators.entry(key).or_insert_with(|| Accumulator {
+                name,
+                artist,
+                path: dir_of(&meta.file),
+                count: 0,
+                sum: [0.0; tree::DIMENSIONS],
+                sum_sq: [0.0; tree::DIMENSIONS],
+            });
+            if acc.count > 0 {
+                acc.path = common_path(&acc.path, &meta.file);
+            }
+            acc.count += 1;
+            for (i, val) in vals.iter().enumerate() {
+                acc.sum[i] += *val as f64;
+                acc.sum_sq[i] += (*val as f64) * (*val as f64);
+            }
+        }
+
+        let mut index = Self::new();
+        for (key, acc) in accumulators {
+            let mut mean = [0.0; tree::DIMENSIONS];
+            let mut stddev = [0.0; tree::DIMENSIONS];
+            let count = acc.count as f64;
+            for i in 0..tree::DIMENSIONS {
+                let m = acc.sum[i] / count;
+                mean[i] = m as f32;
+                stddev[i] = ((acc.sum_sq[i] / count) - (m * m)).max(0.0).sqrt() as f32;
+            }
+            index.keys.insert(key.clone(), index.groups.len());
+            index.groups.push(Group {
+                key,
+                name: acc.name,
+                artist: acc.artist,
+                path: acc.path,
+                num_tracks: acc.count,
+                mean,
+                stddev,
+            });
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Group> {
+        self.keys.get(key).map(|pos| &self.groups[*pos])
+    }
+
+    // Get groups most similar to 'group'. Groups are compared as if each were a normal distribution
+    // of its tracks' features; the distance is the squared 2-Wasserstein distance, so that groups need
+    // both a similar average and a similar spread of features to be considered close.
+    pub fn get_similars(&self, group: &Group, weights: &weights::Weights, min_tracks: usize, count: usize) -> Vec<Similar<'_>> {
+        let mut sims: Vec<Similar> = self.groups.iter()
+            .filter(|g| g.key != group.key && g.num_tracks >= min_tracks)
+            .map(|g| {
+                let mut sim: f32 = 0.0;
+                for (i, w) in weights.iter().enumerate() {
+                    let dm = g.mean[i] - group.mean[i];
+                    let ds = g.stddev[i] - group.stddev[i];
+                    sim += w * w * ((dm * dm) + (ds * ds));
+                }
+                Similar { group: g, sim }
+            })
+            .collect();
+        sims.sort_by(|a, b| a.sim.total_cmp(&b.sim));
+        sims.truncate(count);
+        sims
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::point;
+
+    fn index(groups: &[(&str, f32, f32, usize)]) -> GroupIndex {
+        let mut index = GroupIndex::new();
+        for (key, mean, stddev, num_tracks) in groups {
+            let key: Arc<str> = Arc::from(*key);
+            index.keys.insert(key.clone(), index.groups.len());
+            index.groups.push(Group {
+                key,
+                name: None,
+                artist: None,
+                path: String::new(),
+                num_tracks: *num_tracks,
+                mean: point(&[*mean]),
+                stddev: point(&[*stddev]),
+            });
+        }
+        index
+    }
+
+    fn keys(sims: &[Similar]) -> Vec<String> {
+        sims.iter().map(|s| s.group.key.to_string()).collect()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(common_path("a/b/c.mp3", "a/b/d.mp3"), "a/b/");
+        assert_eq!(common_path("a/b/c.mp3", "a/bc/d.mp3"), "a/");
+        assert_eq!(common_path("a.mp3", "b.mp3"), "");
+        assert_eq!(dir_of("a/b/c.mp3"), "a/b/");
+        assert_eq!(dir_of("c.mp3"), "");
+    }
+
+    #[test]
+    fn similars() {
+        let index = index(&[("a", 0.0, 0.1, 5), ("b", 0.5, 0.1, 5), ("c", 0.0, 0.6, 5), ("d", 0.1, 0.1, 1)]);
+        assert_eq!(index.len(), 4);
+        assert!(index.get("x").is_none());
+        let a = index.get("a").unwrap();
+        // Spread matters, as well as average
+        let sims = index.get_similars(a, &weights::DEFAULT_WEIGHTS, 1, 10);
+        assert_eq!(keys(&sims), vec!["d", "b", "c"]);
+        assert!((sims[0].sim - 0.01).abs() < 1e-6);
+        assert_eq!(keys(&index.get_similars(a, &weights::DEFAULT_WEIGHTS, 2, 1)), vec!["b"]);
+        let mut weights = weights::DEFAULT_WEIGHTS;
+        weights[0] = 0.0;
+        assert!(index.get_similars(a, &weights, 1, 10).iter().all(|s| s.sim == 0.0));
+    }
+}
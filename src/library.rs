@@ -0,0 +1,412 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::cache;
+use crate::db;
+use crate::error::Error;
+use crate::genres;
+use crate::groups;
+use crate::tonal;
+use crate::metadata;
+use crate::tree;
+use crate::weights;
+use globset::Glob;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Max number of trees, built for non-default weights, to keep
+const MAX_CACHED_TREES: usize = 8;
+
+// Data loaded from DB. Requests take a reference to the current snapshot, so
+// a reload can swap in a new one without affecting in-flight requests.
+pub struct Snapshot {
+    pub raw: tree::AnalysisDetails, // Unweighted analysis results
+    positions: HashMap<u64, usize>, // Position of each track in raw
+    ignored: HashMap<u64, [f32; tree::DIMENSIONS]>, // Unweighted analysis results of ignored tracks, which may be seeds
+    default_tree: Arc<tree::Tree>,
+    default_key: Vec<u32>,
+    trees: Mutex<TreeCache>, // Trees built for other weights
+    pub metadata: metadata::MetadataIndex,
+    pub albums: groups::GroupIndex,
+    pub artists: groups::GroupIndex,
+    pub all_db_genres: HashSet<String>,
+    genre_affinity: OnceLock<genres::Affinity>, // Only needed for genremode, so built on first use
+    pub tonal: tonal::Profiles,
+    genre_groups: RwLock<HashMap<String, Arc<Vec<HashSet<String>>>>>, // Named groups from config, expanded
+}
+
+// Trees built for non-default weights, or metrics. Each tree is built once, even if
+// requested concurrently, and the least recently used tree is removed when full.
+#[derive(Default)]
+struct TreeCache {
+    tick: u64,
+    entries: HashMap<Vec<u32>, CachedTree>,
+}
+
+struct CachedTree {
+    last_used: u64,
+    tree: Arc<OnceLock<Arc<tree::Tree>>>, // Empty whilst being built
+}
+
+pub struct Library {
+    db_path: String,
+    weights: weights::Weights,
+    cache: Option<String>, // Path of cached default tree, if enabled
+    profiles: RwLock<HashMap<String, weights::Profile>>,
+    genre_groups: RwLock<HashMap<String, Vec<Vec<String>>>>, // Named groups from config
+    snapshot: RwLock<Arc<Snapshot>>,
+    reload_lock: Mutex<()>,
+    reloading: AtomicBool, // Background reload requested
+}
+
+impl Snapshot {
+    // Load analysis results, and metadata, from DB. Fails if DB cannot be read, so
+    // that a bad DB does not replace a good snapshot. If cache_path is set, then the
+    // default tree is loaded from, or saved to, there.
+    pub fn load(db_path: &str, weights: &weights::Weights, cache_path: Option<&str>) -> Result<Self, Error> {
+        let default_key = weights::key(weights, tree::Metric::default());
+        let cache_key = cache_path.and_then(|path| Some((path, cache::Key::new(db_path, &default_key)?)));
+        let db = db::Db::new(db_path).map_err(Error::Unavailable)?;
+        let (tree_details, default_tree) = match cache_key.as_ref().and_then(|(path, key)| cache::load(path, key)) {
+            Some((raw, tree)) => (raw, Some(tree)),
+            None => (db.load()?, None),
+        };
+        let ignored = db.load_ignored()?;
+        let metadata = metadata::MetadataIndex::load(&db)?;
+        let all_db_genres = db.get_all_genres()?.iter().map(|genre| genre.to_lowercase()).collect();
+        db.close();
+        let default_tree = default_tree.unwrap_or_else(|| {
+            let tree = tree::Tree::new(&tree_details.weighted(weights), tree::Metric::default());
+            if let Some((path, key)) = &cache_key {
+                cache::save(path, key, &tree_details, &tree);
+            }
+            tree
+        });
+        Ok(Self::new(tree_details, ignored, default_tree, default_key, metadata, all_db_genres))
+    }
+
+    // Snapshot without any tracks, used when DB can be uploaded later
+    pub fn empty(weights: &weights::Weights) -> Self {
+        let tree_details = tree::AnalysisDetails::new();
+        let default_tree = tree::Tree::new(&tree_details, tree::Metric::default());
+        Self::new(tree_details, tree::AnalysisDetails::new(), default_tree, weights::key(weights, tree::Metric::default()), metadata::MetadataIndex::new(), HashSet::new())
+    }
+
+    fn new(tree_details: tree::AnalysisDetails, ignored: tree::AnalysisDetails, default_tree: tree::Tree, default_key: Vec<u32>, metadata: metadata::MetadataIndex, all_db_genres: HashSet<String>) -> Self {
+        let albums = groups::GroupIndex::build(&tree_details, &metadata, |m| {
+            match &m.album {
+                Some(album) if !album.is_empty() => Some((m.album_key.clone(), Some(album.clone()), m.album_artist.clone().or(m.artist.clone()))),
+                _ => None,
+            }
+        });
+        log::debug!("Album index contains {} album(s)", albums.len());
+        // Tracks on 'Various Artists' albums are not used, so that compilations do not affect artists
+        let artists = groups::GroupIndex::build(&tree_details, &metadata, |m| {
+            if m.is_various || m.artist_key.is_empty() {
+                None
+            } else {
+                Some((m.artist_key.clone(), m.artist.clone(), None))
+            }
+        });
+        log::debug!("Artist index contains {} artist(s)", artists.len());
+        let tonal = tonal::Profiles::build(&tree_details);
+        Self {
+            default_tree: Arc::new(default_tree),
+            default_key,
+            trees: Mutex::new(TreeCache::default()),
+            positions: tree_details.ids.iter().enumerate().map(|(pos, id)| (*id, pos)).collect(),
+            ignored: ignored.ids.into_iter().zip(ignored.values).collect(),
+            raw: tree_details,
+            metadata,
+            albums,
+            artists,
+            all_db_genres,
+            genre_affinity: OnceLock::new(),
+            tonal,
+            genre_groups: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn genre_groups(&self, name: &str) -> Option<Arc<Vec<HashSet<String>>>> {
+        self.genre_groups.read().unwrap().get(name).cloned()
+    }
+
+    // Expand globs in named genre groups, so that this is not needed per request
+    fn set_genre_groups(&self, groups: &HashMap<String, Vec<Vec<String>>>) {
+        let mut expanded = HashMap::new();
+        for (name, group) in groups {
+            match expand_globbed_genres(group, &self.all_db_genres) {
+                Ok(g) => { expanded.insert(name.clone(), Arc::new(g)); }
+                Err(e) => { log::error!("Genre groups '{}'. {}", name, e); }
+            }
+        }
+        *self.genre_groups.write().unwrap() = expanded;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.ids.is_empty()
+    }
+
+    // Get affinity between genres, building if required
+    pub fn genre_affinity(&self) -> &genres::Affinity {
+        self.genre_affinity.get_or_init(|| genres::Affinity::build(&self.raw, &self.metadata))
+    }
+
+    pub fn has_genre_affinity(&self) -> bool {
+        self.genre_affinity.get().is_some()
+    }
+
+    // Position of track in raw, if not ignored
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.positions.get(&id).copied()
+    }
+
+    // Get unweighted analysis results of track
+    pub fn raw_metrics(&self, id: u64) -> Result<[f32; tree::DIMENSIONS], Error> {
+        match self.position(id) {
+            Some(pos) => Ok(self.raw.values[pos]),
+            None => self.ignored.get(&id).copied().ok_or_else(|| Error::UnknownTrack(match self.metadata.get(id) {
+                Some(m) => m.file.to_string(),
+                None => id.to_string(),
+            })),
+        }
+    }
+
+    // Get analysis results of track, with weights applied
+    pub fn metrics(&self, id: u64, weights: &weights::Weights) -> Result<[f32; tree::DIMENSIONS], Error> {
+        Ok(weights::apply(&self.raw_metrics(id)?, weights))
+    }
+
+    // Get unweighted analysis results of artist's tracks
+    pub fn artist_details(&self, artist: &str) -> tree::AnalysisDetails {
+        let mut details = tree::AnalysisDetails::new();
+        for (id, vals) in self.raw.ids.iter().zip(self.raw.values.iter()) {
+            if self.metadata.get(*id).is_some_and(|m| m.artist.as_deref() == Some(artist)) {
+                details.ids.push(*id);
+                details.values.push(*vals);
+            }
+        }
+        log::debug!("Artist '{}' has {} track(s)", artist, details.ids.len());
+        details
+    }
+
+    // Get tree built using supplied weights, and metric, if this has already been built
+    pub fn cached_tree(&self, weights: &weights::Weights, metric: tree::Metric) -> Option<Arc<tree::Tree>> {
+        let key = weights::key(weights, metric);
+        if key == self.default_key {
+            return Some(self.default_tree.clone());
+        }
+        let mut trees = self.trees.lock().unwrap();
+        trees.tick += 1;
+        let tick = trees.tick;
+        let entry = trees.entries.get_mut(&key)?;
+        entry.last_used = tick;
+        entry.tree.get().cloned()
+    }
+
+    // Get tree built using supplied weights, and metric, building if required. If another
+    // thread is building the same tree, then wait for that to finish.
+    pub fn tree(&self, weights: &weights::Weights, metric: tree::Metric) -> Arc<tree::Tree> {
+        let key = weights::key(weights, metric);
+        if key == self.default_key {
+            return self.default_tree.clone();
+        }
+        let cell = {
+            let mut trees = self.trees.lock().unwrap();
+            trees.tick += 1;
+            let tick = trees.tick;
+            if !trees.entries.contains_key(&key) && trees.entries.len() >= MAX_CACHED_TREES {
+                let oldest = trees.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    trees.entries.remove(&oldest);
+                }
+            }
+            let entry = trees.entries.entry(key).or_insert_with(|| CachedTree { last_used: tick, tree: Arc::new(OnceLock::new()) });
+            entry.last_used = tick;
+            entry.tree.clone()
+        };
+        cell.get_or_init(|| {
+            log::debug!("Building tree for weights {:?}, metric {:?}", weights, metric);
+            Arc::new(tree::Tree::new(&self.raw.weighted(weights), metric))
+        }).clone()
+    }
+}
+
+impl Library {
+    // Load library from DB. If allow_empty is set (e.g. DB may be uploaded later) then failing
+    // to read the DB results in an empty library, rather than an error.
+    pub fn new(db_path: &str, weights: weights::Weights, cache: bool, allow_empty: bool, profiles: HashMap<String, weights::Profile>, genre_groups: HashMap<String, Vec<Vec<String>>>) -> Result<Self, Error> {
+        let cache = if cache { Some(cache::path(db_path)) } else { None };
+        let snapshot = match Snapshot::load(db_path, &weights, cache.as_deref()) {
+            Ok(snapshot) => snapshot,
+            Err(e) if allow_empty => {
+                log::warn!("Failed to load {}. {}", db_path, e);
+                Snapshot::empty(&weights)
+            }
+            Err(e) => { return Err(e); }
+        };
+        snapshot.set_genre_groups(&genre_groups);
+        Ok(Self {
+            db_path: db_path.to_string(),
+            weights,
+            cache,
+            profiles: RwLock::new(profiles),
+            genre_groups: RwLock::new(genre_groups),
+            snapshot: RwLock::new(Arc::new(snapshot)),
+            reload_lock: Mutex::new(()),
+            reloading: AtomicBool::new(false),
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    // Get weights for request; either supplied values, a named profile, or the defaults
+    pub fn weights(&self, profile: &Option<String>, vals: &Option<Vec<f32>>) -> Result<weights::Weights, Error> {
+        if let Some(vals) = vals {
+            return weights::from_values(vals);
+        }
+        if let Some(name) = profile {
+            return match self.profiles.read().unwrap().get(name) {
+                Some(profile) => Ok(profile.weights),
+                None => Err(Error::UnknownProfile(name.clone())),
+            };
+        }
+        Ok(self.weights)
+    }
+
+    // Get distance metric for request; either supplied metric, that of named profile, or the default
+    pub fn metric(&self, profile: &Option<String>, metric: &Option<String>) -> Result<tree::Metric, Error> {
+        if let Some(val) = metric {
+            return tree::Metric::parse(val).ok_or_else(|| Error::InvalidParams(format!("Unknown metric '{}'", val)));
+        }
+        if let Some(name) = profile {
+            return match self.profiles.read().unwrap().get(name) {
+                Some(profile) => Ok(profile.metric.unwrap_or_default()),
+                None => Err(Error::UnknownProfile(name.clone())),
+            };
+        }
+        Ok(tree::Metric::default())
+    }
+
+    pub fn set_profiles(&self, profiles: HashMap<String, weights::Profile>) {
+        *self.profiles.write().unwrap() = profiles;
+    }
+
+    // Store groups, and expand for current snapshot. Holds reload lock so that a concurrent
+    // reload cannot build its snapshot from the previous groups.
+    pub fn set_genre_groups(&self, genre_groups: HashMap<String, Vec<Vec<String>>>) {
+        let _guard = self.reload_lock.lock().unwrap();
+        *self.genre_groups.write().unwrap() = genre_groups;
+        self.snapshot().set_genre_groups(&self.genre_groups.read().unwrap());
+    }
+
+    pub fn default_weights(&self) -> &weights::Weights {
+        &self.weights
+    }
+
+    // Reload DB in calling thread. If another reload is in progress, wait for
+    // that to finish first - as it may have read the DB before it was changed.
+    // If the DB cannot be read, the current snapshot is kept.
+    pub fn reload_now(&self) -> Result<(), Error> {
+        let _guard = self.reload_lock.lock().unwrap();
+        log::info!("Reloading {}", self.db_path);
+        let result = Snapshot::load(&self.db_path, &self.weights, self.cache.as_deref()).map(|snapshot| {
+            snapshot.set_genre_groups(&self.genre_groups.read().unwrap());
+            *self.snapshot.write().unwrap() = Arc::new(snapshot);
+        });
+        match &result {
+            Ok(_) => { log::info!("Reload complete"); }
+            Err(e) => { log::error!("Failed to reload {}, keeping current library. {}", self.db_path, e); }
+        }
+        result
+    }
+
+    // Replace DB with file at path, e.g. an upload. The new DB is loaded before it is moved
+    // into place, so that the DB file, and snapshot, are swapped together. If the new DB
+    // cannot be read then the current DB, and snapshot, are kept.
+    pub fn replace(&self, path: &str) -> Result<(), Error> {
+        let _guard = self.reload_lock.lock().unwrap();
+        log::info!("Loading {}", path);
+        let snapshot = Snapshot::load(path, &self.weights, self.cache.as_deref())?;
+        snapshot.set_genre_groups(&self.genre_groups.read().unwrap());
+        fs::rename(path, &self.db_path).map_err(|e| Error::Internal(format!("Failed to rename {} to {}. {}", path, self.db_path, e)))?;
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
+        log::info!("Replaced {}", self.db_path);
+        Ok(())
+    }
+
+    // Reload DB in a background thread. Returns false if a background reload was already
+    // requested, and has not yet finished.
+    pub fn reload(self: &Arc<Self>) -> bool {
+        if self.reloading.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return false;
+        }
+        let library = self.clone();
+        thread::spawn(move || {
+            let _ = library.reload_now();
+            library.reloading.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    // Poll DB file for changes, and reload once it has stopped changing.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let library = self.clone();
+        thread::spawn(move || {
+            let mut last = file_stamp(&library.db_path);
+            let mut pending = false;
+            loop {
+                thread::sleep(interval);
+                let current = file_stamp(&library.db_path);
+                if current != last {
+                    log::debug!("{} has changed", library.db_path);
+                    last = current;
+                    pending = true;
+                } else if pending && current.is_some() {
+                    // Try again next time if DB could not be read, e.g. as it was locked
+                    pending = library.reload_now().is_err();
+                }
+            }
+        });
+    }
+}
+
+pub fn expand_globbed_genres(genregroups: &Vec<Vec<String>>, all_db_genres: &HashSet<String>) -> Result<Vec<HashSet<String>>, Error> {
+    let mut expanded: Vec<HashSet<String>> = Vec::new();
+
+    for group in genregroups {
+        let mut gset: HashSet<String> = HashSet::new();
+        for genre in group {
+            let lgenre = genre.to_lowercase();
+            let glob = Glob::new(&lgenre).map_err(|e| Error::InvalidGenre(e.to_string()))?.compile_matcher();
+            for item in all_db_genres {
+                if glob.is_match(item) {
+                    gset.insert(item.to_string());
+                }
+            }
+        }
+        expanded.push(gset);
+    }
+    Ok(expanded)
+}
+
+fn file_stamp(path: &str) -> Option<(SystemTime, u64)> {
+    match fs::metadata(path) {
+        Ok(meta) => match meta.modified() {
+            Ok(modified) => Some((modified, meta.len())),
+            Err(_) => None,
+        },
+        Err(_) => None,
+    }
+}
@@ -6,20 +6,88 @@
  *
  **/
 
+#[cfg(unix)]
+use actix_web::rt::signal::unix::{signal, SignalKind};
 use actix_web::{client, middleware::Logger, web, App, HttpServer};
 use argparse::{ArgumentParser, Store, StoreTrue};
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::path::Path;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 use rayon::ThreadPoolBuilder;
 mod api;
+mod blocklist;
+mod cache;
+mod config;
 mod db;
+mod diversity;
+mod energy;
+mod error;
+mod feedback;
 mod forest;
+mod genres;
+mod groups;
+#[cfg(test)]
+mod testutils;
+mod tonal;
+mod library;
+mod metadata;
 mod tree;
 mod upload;
+mod weights;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Get weight profiles from profiles file, and config. Config profiles replace any of the same name.
+fn load_profiles(profiles_path: &str, config: &config::Config) -> Result<HashMap<String, weights::Profile>, error::Error> {
+    let mut profiles = HashMap::new();
+    if !profiles_path.is_empty() {
+        profiles = weights::load_profiles(profiles_path)?;
+    }
+    profiles.extend(weights::parse_profiles(&config.profiles)?);
+    Ok(profiles)
+}
+
+// Re-read config, and profiles. These are only applied if all are valid, otherwise
+// the current config is kept.
+#[cfg(unix)]
+fn reload_config(settings: &config::Settings, library: &library::Library, profiles_path: &str) -> Result<(), error::Error> {
+    let config = settings.load()?;
+    let profiles = load_profiles(profiles_path, &config)?;
+    library.set_profiles(profiles);
+    library.set_genre_groups(config.genregroups.clone());
+    settings.replace(config);
+    Ok(())
+}
+
+// Re-read config (and profiles) on SIGHUP. Setting genre groups waits for any DB
+// reload in progress, so this is done on a blocking thread.
+#[cfg(unix)]
+fn handle_sighup(settings: web::Data<config::Settings>, library: web::Data<library::Library>, profiles_path: String) {
+    actix_web::rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("Failed to install SIGHUP handler. {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            let (settings, library, profiles_path) = (settings.clone(), library.clone(), profiles_path.clone());
+            match web::block(move || reload_config(&settings, &library, &profiles_path)).await {
+                Ok(_) => { log::info!("Config reloaded"); }
+                Err(e) => { log::error!("Failed to reload config. {}", e); }
+            }
+        }
+    });
+}
+
+// No SIGHUP on this platform, so config is only read at startup
+#[cfg(not(unix))]
+fn handle_sighup(_settings: web::Data<config::Settings>, _library: web::Data<library::Library>, _profiles_path: String) {
+}
+
 async fn send_port_to_lms(lms_server: &String, port: u16) {
     if !lms_server.is_empty() {
         // Inform LMS of port number in use
@@ -48,13 +116,30 @@ async fn send_port_to_lms(lms_server: &String, port: u16) {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let mut db_path = "bliss.db".to_string();
-    let mut port: u16 = 12000;
-    let mut address = "0.0.0.0".to_string();
-    let mut logging = "warn".to_string();
-    let mut lms_server = String::new();
-    let mut allow_db_upload = false;
-    let mut weights = String::new();
+    // Config file values are used as defaults, so read before parsing command-line
+    let config_path = config::path_from_args(&std::env::args().collect::<Vec<String>>());
+    let config = match &config_path {
+        Some(path) => match config::load(path) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(-1);
+            }
+        },
+        None => config::Config::default(),
+    };
+    let mut config_file = config_path.clone().unwrap_or_default();
+    let mut db_path = config.db.clone().unwrap_or("bliss.db".to_string());
+    let mut port: u16 = config.port.unwrap_or(12000);
+    let mut address = config.address.clone().unwrap_or("0.0.0.0".to_string());
+    let mut logging = config.logging.clone().unwrap_or("warn".to_string());
+    let mut lms_server = config.lms.clone().unwrap_or_default();
+    let mut allow_db_upload = config.upload.unwrap_or(false);
+    let mut weights = config.weights.clone().unwrap_or_default();
+    let mut profiles = String::new();
+    let mut watch: u64 = config.watch.unwrap_or(0);
+    let mut state_path = config.state.clone().unwrap_or_default();
+    let mut cache = config.cache.unwrap_or(false);
     {
         let db_path_help = format!("Database location (default: {})", db_path);
         let port_help = format!("Port number (default: {})", port);
@@ -66,6 +151,7 @@ async fn main() -> std::io::Result<()> {
         // borrow per scope, hence this section is enclosed in { }
         let mut arg_parse = ArgumentParser::new();
         arg_parse.set_description(&description);
+        arg_parse.refer(&mut config_file).add_option(&["-c", "--config"], Store, "Config file (TOML, or JSON). Command-line options override those in config file");
         arg_parse.refer(&mut db_path).add_option(&["-d", "--db"], Store, &db_path_help);
         arg_parse.refer(&mut port).add_option(&["-p", "--port"], Store, &port_help);
         arg_parse.refer(&mut address).add_option(&["-a", "--address"], Store, &address_help);
@@ -73,6 +159,10 @@ async fn main() -> std::io::Result<()> {
         arg_parse.refer(&mut lms_server).add_option(&["-L", "--lms"], Store, "LMS server (hostname:port, or IP address:port)");
         arg_parse.refer(&mut allow_db_upload).add_option(&["-u", "--upload"], StoreTrue, "Allow uploading of database");
         arg_parse.refer(&mut weights).add_option(&["-w", "--weights"], Store, &weights_help);
+        arg_parse.refer(&mut profiles).add_option(&["-P", "--profiles"], Store, "JSON file of named weight profiles");
+        arg_parse.refer(&mut watch).add_option(&["-W", "--watch"], Store, "Check database for changes every N seconds, and reload if changed (default: 0, disabled)");
+        arg_parse.refer(&mut state_path).add_option(&["-s", "--state"], Store, "Database used to store blocklists and feedback (default: bliss-mixer.db, in same folder as database)");
+        arg_parse.refer(&mut cache).add_option(&["-C", "--cache"], StoreTrue, "Cache similarity tree next to database, for faster startup");
         arg_parse.parse_args_or_exit();
     }
 
@@ -108,51 +198,109 @@ async fn main() -> std::io::Result<()> {
         port = 0;
     }
 
-    if allow_db_upload {
-        log::info!("Starting in upload mode");
-        let server = HttpServer::new(move || {
-            App::new()
-                .data(db_path.clone())
-                .app_data(web::PayloadConfig::new(200 * 1024 * 1024))
-                .route("/upload", web::put().to(upload::handle_upload))
-        }).bind((address, port))?;
-        send_port_to_lms(&lms_server, server.addrs()[0].port()).await;
+    log::info!("Starting in mix mode{}", if allow_db_upload { ", with DB upload" } else { "" });
+    let mut default_weights = weights::DEFAULT_WEIGHTS;
+    if !weights.is_empty() {
+        match weights::parse(&weights) {
+            Ok(w) => { default_weights = w; }
+            Err(e) => {
+                log::error!("{}", e);
+                process::exit(-1);
+            }
+        }
+    }
+    let weight_profiles = match load_profiles(&profiles, &config) {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("{}", e);
+            process::exit(-1);
+        }
+    };
+    let library = match library::Library::new(&db_path, default_weights, cache, allow_db_upload, weight_profiles, config.genregroups.clone()) {
+        Ok(l) => Arc::new(l),
+        Err(e) => {
+            log::error!("Failed to load {}. {}", db_path, e);
+            process::exit(-1);
+        }
+    };
+    if watch > 0 {
+        library.watch(Duration::from_secs(watch));
+    }
+    let library_data = web::Data::from(library);
+    let settings = web::Data::new(config::Settings::new(config_path, config));
+    handle_sighup(settings.clone(), library_data.clone(), profiles);
 
-        server.run().await
-    } else {
-        log::info!("Starting in mix mode");
-        if !weights.is_empty() {
-            db::init_weights(&weights);
+    if state_path.is_empty() {
+        state_path = path.with_file_name("bliss-mixer.db").to_string_lossy().to_string();
+    }
+    let blocklist = match blocklist::Blocklist::open(&state_path) {
+        Ok(b) => web::Data::new(b),
+        Err(e) => {
+            log::error!("Failed to open {}. {}", state_path, e);
+            process::exit(-1);
         }
-        let mut all_db_genres = HashSet::new();
-        let mut tree_details = tree::AnalysisDetails::new();
-        if path.exists() {
-            let db = db::Db::new(&db_path);
-            tree_details = db.load();
-            for genre in db.get_all_genres() {
-                all_db_genres.insert(genre.to_lowercase());
-            }
-            db.close();
+    };
+    let feedback = match feedback::Feedback::open(&state_path) {
+        Ok(f) => web::Data::new(f),
+        Err(e) => {
+            log::error!("Failed to open {}. {}", state_path, e);
+            process::exit(-1);
         }
-        let tree = tree::Tree::new(&tree_details);
+    };
+
+    let total_cpus:usize = num_cpus::get() as usize;
+    if total_cpus>1 {
+        ThreadPoolBuilder::new().num_threads(total_cpus - 1).build_global().unwrap();
+    }
 
-        let total_cpus:usize = num_cpus::get() as usize;
-        if total_cpus>1 {
-            ThreadPoolBuilder::new().num_threads(total_cpus - 1).build_global().unwrap();
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
+            .wrap(Logger::new("%a %{User-Agent}i"))
+            .app_data(library_data.clone())
+            .app_data(blocklist.clone())
+            .app_data(feedback.clone())
+            .app_data(settings.clone())
+            .app_data(web::JsonConfig::default().error_handler(error::json_error))
+            .data(db_path.clone())
+            .route("/api/mix", web::post().to(api::mix))
+            .route("/api/list", web::post().to(api::list))
+            .route("/api/path", web::post().to(api::path))
+            .route("/api/albums/similar", web::post().to(api::similar_albums))
+            .route("/api/artists/similar", web::post().to(api::similar_artists))
+            .route("/api/ready", web::get().to(api::ready))
+            .route("/api/blocklist", web::get().to(api::get_blocklist))
+            .route("/api/blocklist", web::post().to(api::add_to_blocklist))
+            .route("/api/blocklist", web::delete().to(api::remove_from_blocklist))
+            .route("/api/feedback", web::post().to(api::feedback))
+            .route("/api/reload", web::post().to(api::reload));
+        if allow_db_upload {
+            app = app
+                .app_data(web::PayloadConfig::new(200 * 1024 * 1024))
+                .route("/upload", web::put().to(upload::handle_upload));
         }
+        app
+    }).bind((address, port))?;
+    send_port_to_lms(&lms_server, server.addrs()[0].port()).await;
+
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::temp_path;
 
-        let server = HttpServer::new(move || {
-            App::new()
-                .wrap(Logger::new("%a %{User-Agent}i"))
-                .data(tree.clone())
-                .data(all_db_genres.clone())
-                .data(db_path.clone())
-                .route("/api/mix", web::post().to(api::mix))
-                .route("/api/list", web::post().to(api::list))
-                .route("/api/ready", web::get().to(api::ready))
-        }).bind((address, port))?;
-        send_port_to_lms(&lms_server, server.addrs()[0].port()).await;
-
-        server.run().await
+    #[test]
+    fn config_profiles_override_file() {
+        let path = temp_path("profiles.json");
+        std::fs::write(&path, r#"{ "quiet": "0.5", "loud": "2" }"#).unwrap();
+        let config: config::Config = toml::from_str("[profiles]\nquiet = \"0.25\"\nslow = \"3\"").unwrap();
+        let profiles = load_profiles(&path, &config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(profiles["quiet"].weights[0], 0.25);
+        assert_eq!(profiles["loud"].weights[0], 2.0);
+        assert_eq!(profiles["slow"].weights[0], 3.0);
+        assert_eq!(load_profiles("", &config).unwrap().len(), 2);
     }
 }
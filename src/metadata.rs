@@ -0,0 +1,161 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+const VARIOUS: &str = "various";
+const VARIOUS_ARTISTS: &str = "various artists";
+
+// Metadata of a track. Artist, album, and genre strings are shared between
+// tracks, and lowercase versions (used for filtering) are pre-calculated.
+pub struct TrackMeta {
+    pub file: Arc<str>,
+    pub title: Option<Arc<str>>,
+    pub artist: Option<Arc<str>>,
+    pub album_artist: Option<Arc<str>>,
+    pub album: Option<Arc<str>>,
+    pub genre_names: Arc<Vec<String>>,
+    pub duration: u32,
+    pub bpm: i16,
+    pub loudness: f32, // Mean loudness (dB), NaN if unknown
+    pub dynamics: f32, // Standard deviation of loudness (dB), NaN if unknown
+    pub year: u16, // 0 if unknown
+    // Lowercase keys
+    pub title_key: Arc<str>,
+    pub artist_key: Arc<str>,
+    pub album_key: Arc<str>, // album::albumartist, or album::artist if no album artist
+    pub genres: Arc<HashSet<String>>,
+    pub is_various: bool,
+}
+
+pub struct MetadataIndex {
+    tracks: HashMap<u64, TrackMeta>,
+    files: HashMap<Arc<str>, u64>,
+}
+
+// Original genre names, and lowercase genres
+type Genres = (Arc<Vec<String>>, Arc<HashSet<String>>);
+
+// Store single copy of each string
+#[derive(Default)]
+struct Interner {
+    strings: HashMap<String, Arc<str>>,
+    genres: HashMap<String, Genres>,
+}
+
+impl Interner {
+    fn get(&mut self, val: &str) -> Arc<str> {
+        if let Some(s) = self.strings.get(val) {
+            return s.clone();
+        }
+        let s: Arc<str> = Arc::from(val);
+        self.strings.insert(val.to_string(), s.clone());
+        s
+    }
+
+    fn get_opt(&mut self, val: &Option<String>) -> Option<Arc<str>> {
+        val.as_ref().map(|v| self.get(v))
+    }
+
+    fn get_genres(&mut self, val: &Option<String>) -> Genres {
+        let genre = val.as_deref().unwrap_or_default();
+        if let Some(g) = self.genres.get(genre) {
+            return g.clone();
+        }
+        let mut names: Vec<String> = Vec::new();
+        let mut keys: HashSet<String> = HashSet::new();
+        for g in genre.split(';') {
+            let trimmed = g.trim();
+            if !trimmed.is_empty() {
+                names.push(String::from(trimmed));
+                keys.insert(trimmed.to_lowercase());
+            }
+        }
+        let entry = (Arc::new(names), Arc::new(keys));
+        self.genres.insert(genre.to_string(), entry.clone());
+        entry
+    }
+}
+
+fn tempo_to_bpm(tempo: Option<f32>) -> i16 {
+    (((tempo.unwrap_or(0.0)+1.0)*206.0)/2.0) as i16
+}
+
+// Loudness features are normalised to -1..1, from a range of 90dB
+fn to_db(val: Option<f32>) -> f32 {
+    val.map_or(f32::NAN, |v| ((v+1.0)*90.0)/2.0)
+}
+
+impl MetadataIndex {
+    pub fn new() -> Self {
+        Self {
+            tracks: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn load(db: &db::Db) -> Result<Self, Error> {
+        let mut index = Self::new();
+        let mut interner = Interner::default();
+
+        for (id, m) in db.get_all_metadata()? {
+            let artist_key = interner.get(&m.artist.as_deref().unwrap_or_default().to_lowercase());
+            let album_artist_key = m.album_artist.as_deref().unwrap_or_default().to_lowercase();
+            let album = m.album.as_deref().unwrap_or_default().to_lowercase();
+            let (album_key, is_various) = if album_artist_key.is_empty() {
+                (interner.get(&(album + "::" + &artist_key)), false)
+            } else {
+                (interner.get(&(album + "::" + &album_artist_key)),
+                 album_artist_key == VARIOUS || album_artist_key == VARIOUS_ARTISTS)
+            };
+            let (genre_names, genres) = interner.get_genres(&m.genre);
+            let file: Arc<str> = Arc::from(m.file.as_str());
+            let meta = TrackMeta {
+                file: file.clone(),
+                title: m.title.as_deref().map(Arc::from),
+                artist: interner.get_opt(&m.artist),
+                album_artist: interner.get_opt(&m.album_artist),
+                album: interner.get_opt(&m.album),
+                genre_names,
+                duration: m.duration.unwrap_or(0),
+                bpm: tempo_to_bpm(m.tempo),
+                loudness: to_db(m.loudness) - 90.0,
+                dynamics: to_db(m.dynamics),
+                year: m.year.unwrap_or(0),
+                title_key: Arc::from(m.title.unwrap_or_default().to_lowercase().as_str()),
+                artist_key,
+                album_key,
+                genres,
+                is_various,
+            };
+            index.files.insert(file, id);
+            index.tracks.insert(id, meta);
+        }
+        log::debug!("Metadata index contains {} track(s)", index.tracks.len());
+        Ok(index)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&TrackMeta> {
+        self.tracks.get(&id)
+    }
+
+    pub fn get_rowid(&self, path: &str) -> u64 {
+        self.files.get(path).copied().unwrap_or(0)
+    }
+
+    // Get first track, ordered by path, whose path starts with prefix
+    pub fn find_by_prefix(&self, prefix: &str) -> Option<&TrackMeta> {
+        self.files.iter()
+            .filter(|(file, _)| file.starts_with(prefix))
+            .min_by(|a, b| a.0.cmp(b.0))
+            .and_then(|(_, id)| self.tracks.get(id))
+    }
+}
@@ -0,0 +1,21 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::tree::DIMENSIONS;
+
+// Analysis point with the given leading values, and all others zero
+pub fn point(vals: &[f32]) -> [f32; DIMENSIONS] {
+    let mut point = [0.0; DIMENSIONS];
+    point[..vals.len()].copy_from_slice(vals);
+    point
+}
+
+// Path, unique to this test process, for a temporary file
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(format!("bliss-mixer-{}-{}", std::process::id(), name)).to_string_lossy().to_string()
+}
@@ -0,0 +1,137 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::tree;
+use bliss_audio::AnalysisIndex;
+use std::collections::HashMap;
+
+const NUM_CHROMA: usize = 13;
+// Tracks less similar than this are discarded when tonal similarity is required
+pub const MIN_SIMILARITY: f32 = 0.6;
+
+#[derive(Clone, Copy)]
+pub enum Mode {
+    Prefer, // Add to distance of tracks that are not similar
+    Require, // Discard tracks that are not similar
+}
+
+// Tonal profile of each track, derived from its chroma features. Features are
+// relative to the library's average, so that profiles describe how a track's
+// tonality differs from the norm - and are then scaled to unit length.
+// Note: bliss's chroma features describe intervals and major/minor modes, and
+// are invariant to key - so this is a proxy for tonal similarity, and is NOT
+// a key estimate. As the same features are also part of the distance used to
+// find tracks, Prefer weights them twice.
+pub struct Profiles {
+    profiles: HashMap<u64, [f32; NUM_CHROMA]>,
+}
+
+impl Mode {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val.to_lowercase().as_str() {
+            "prefer" => Some(Mode::Prefer),
+            "require" => Some(Mode::Require),
+            _ => None,
+        }
+    }
+}
+
+impl Profiles {
+    pub fn new() -> Self {
+        Self {
+            profiles: HashMap::new(),
+        }
+    }
+
+    pub fn build(details: &tree::AnalysisDetails) -> Self {
+        let mut profiles = Self::new();
+        if details.values.is_empty() {
+            return profiles;
+        }
+        let first = AnalysisIndex::Chroma1 as usize;
+        let mut means = [0.0f64; NUM_CHROMA];
+        for vals in &details.values {
+            for (i, mean) in means.iter_mut().enumerate() {
+                *mean += vals[first + i] as f64;
+            }
+        }
+        for mean in means.iter_mut() {
+            *mean /= details.values.len() as f64;
+        }
+
+        for (id, vals) in details.ids.iter().zip(details.values.iter()) {
+            let mut profile = [0.0f32; NUM_CHROMA];
+            for (i, val) in profile.iter_mut().enumerate() {
+                *val = (vals[first + i] as f64 - means[i]) as f32;
+            }
+            let len = profile.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if len > 0.0 {
+                for val in profile.iter_mut() {
+                    *val /= len;
+                }
+                profiles.profiles.insert(*id, profile);
+            }
+        }
+        log::debug!("Built tonal profiles for {} track(s)", profiles.profiles.len());
+        profiles
+    }
+
+    // Similarity, from 0 (opposite) to 1 (same), of two tracks' tonal profiles. Tracks
+    // without a profile are considered similar.
+    pub fn similarity(&self, a: u64, b: u64) -> f32 {
+        match (self.profiles.get(&a), self.profiles.get(&b)) {
+            (Some(pa), Some(pb)) => {
+                let cos: f32 = pa.iter().zip(pb.iter()).map(|(x, y)| x * y).sum();
+                (1.0 + cos.clamp(-1.0, 1.0)) / 2.0
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Analysis with the given leading chroma values
+    fn details(chroma: &[[f32; 2]]) -> tree::AnalysisDetails {
+        let first = AnalysisIndex::Chroma1 as usize;
+        let mut details = tree::AnalysisDetails::new();
+        for (id, vals) in chroma.iter().enumerate() {
+            let mut point = [0.0; tree::DIMENSIONS];
+            point[first..first + 2].copy_from_slice(vals);
+            details.ids.push(id as u64 + 1);
+            details.values.push(point);
+        }
+        details
+    }
+
+    #[test]
+    fn parse() {
+        assert!(matches!(Mode::parse("Prefer"), Some(Mode::Prefer)));
+        assert!(matches!(Mode::parse("require"), Some(Mode::Require)));
+        assert!(Mode::parse("key").is_none());
+    }
+
+    #[test]
+    fn similarity() {
+        let profiles = Profiles::build(&details(&[[1.0, 0.0], [-1.0, 0.0], [0.0, 0.0], [0.0, 2.0], [0.0, -2.0]]));
+        assert!((profiles.similarity(1, 1) - 1.0).abs() < 1e-6);
+        assert!(profiles.similarity(1, 2).abs() < 1e-6);
+        assert!((profiles.similarity(1, 4) - 0.5).abs() < 1e-6);
+        assert_eq!(profiles.similarity(2, 1), profiles.similarity(1, 2));
+        // Tracks with average tonality, or not in the library, have no profile
+        assert_eq!(profiles.similarity(1, 3), 1.0);
+        assert_eq!(profiles.similarity(1, 10), 1.0);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(Profiles::build(&tree::AnalysisDetails::new()).similarity(1, 2), 1.0);
+    }
+}
@@ -6,17 +6,35 @@
  *
  **/
 
-use kiddo::{ImmutableKdTree, SquaredEuclidean};
+use crate::weights;
+use kiddo::{ImmutableKdTree, Manhattan, SquaredEuclidean};
+use serde::{Deserialize, Serialize};
 use std::num::NonZero;
 
 pub const DIMENSIONS: usize = bliss_audio::NUMBER_FEATURES;
 
-#[derive(Clone)]
+// Distance between tracks. Euclidean, and cosine, neighbours are found using squared
+// euclidean distance, as this gives the same order. KD-tree pruning does not work with
+// Chebyshev distance, so all tracks are checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    #[default]
+    SquaredEuclidean,
+    Euclidean,
+    Manhattan,
+    Cosine, // 1 - cosine similarity
+    Chebyshev,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Tree {
-    pub tree: ImmutableKdTree<f32, DIMENSIONS>,
-    idmap: Vec<u64> // List of rowids
+    pub tree: Option<ImmutableKdTree<f32, DIMENSIONS>>, // None if no tracks, or using Chebyshev distance
+    idmap: Vec<u64>, // List of rowids
+    metric: Metric,
+    values: Vec<[f32; DIMENSIONS]>, // Only kept when using Chebyshev distance
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct AnalysisDetails {
     pub values: Vec<[f32; DIMENSIONS]>,
     pub ids: Vec<u64>
@@ -29,6 +47,13 @@ impl AnalysisDetails {
             ids: Vec::new()
         }
     }
+
+    pub fn weighted(&self, weights: &weights::Weights) -> Self {
+        Self {
+            values: self.values.iter().map(|v| weights::apply(v, weights)).collect(),
+            ids: self.ids.clone()
+        }
+    }
 }
 
 pub struct Sim {
@@ -36,25 +61,133 @@ pub struct Sim {
     pub sim: f32,
 }
 
+impl Metric {
+    pub fn parse(val: &str) -> Option<Self> {
+        match val.to_lowercase().as_str() {
+            "squaredeuclidean" => Some(Metric::SquaredEuclidean),
+            "euclidean" => Some(Metric::Euclidean),
+            "manhattan" => Some(Metric::Manhattan),
+            "cosine" => Some(Metric::Cosine),
+            "chebyshev" => Some(Metric::Chebyshev),
+            _ => None,
+        }
+    }
+
+    pub fn distance(&self, a: &[f32; DIMENSIONS], b: &[f32; DIMENSIONS]) -> f32 {
+        match self {
+            Metric::SquaredEuclidean => squared_euclidean(a, b),
+            Metric::Euclidean => squared_euclidean(a, b).sqrt(),
+            Metric::Manhattan => a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum(),
+            Metric::Cosine => squared_euclidean(&normalised(a), &normalised(b)) / 2.0,
+            Metric::Chebyshev => a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max),
+        }
+    }
+}
+
+fn squared_euclidean(a: &[f32; DIMENSIONS], b: &[f32; DIMENSIONS]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+// Scale to unit length. For unit vectors, squared euclidean distance is 2 * (1 - cosine similarity)
+fn normalised(vals: &[f32; DIMENSIONS]) -> [f32; DIMENSIONS] {
+    let len = vals.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if len > 0.0 { vals.map(|v| v / len) } else { *vals }
+}
+
 impl Tree {
-    pub fn new(details: &AnalysisDetails) -> Self {
+    pub fn new(details: &AnalysisDetails, metric: Metric) -> Self {
+        let values: Vec<[f32; DIMENSIONS]> = match metric {
+            Metric::Cosine => details.values.iter().map(normalised).collect(),
+            _ => details.values.clone(),
+        };
+        let use_tree = !values.is_empty() && metric != Metric::Chebyshev;
         Self {
-            tree: ImmutableKdTree::new_from_slice(&details.values),
-            idmap: details.ids.clone()
+            tree: if use_tree { Some(ImmutableKdTree::new_from_slice(&values)) } else { None },
+            idmap: details.ids.clone(),
+            metric,
+            values: if use_tree { Vec::new() } else { values },
+        }
+    }
+
+    // Check that tree, e.g. when loaded from cache, holds the expected number of tracks
+    pub fn is_valid(&self, num_tracks: usize) -> bool {
+        self.idmap.len() == num_tracks && match &self.tree {
+            Some(tree) => tree.size() == num_tracks,
+            None => self.values.len() == num_tracks,
         }
     }
 
     pub fn get_similars(&self, seed: &[f32; DIMENSIONS], count: NonZero<usize>) -> Vec<Sim> {
         let mut resp = Vec::<Sim>::new();
+        if self.metric == Metric::Chebyshev {
+            let mut all: Vec<Sim> = self.values.iter().zip(self.idmap.iter()).map(|(vals, id)| Sim { id: *id, sim: self.metric.distance(seed, vals) }).collect();
+            if all.len() > count.get() {
+                all.select_nth_unstable_by(count.get() - 1, |a, b| a.sim.total_cmp(&b.sim));
+                all.truncate(count.get());
+            }
+            all.sort_by(|a, b| a.sim.total_cmp(&b.sim));
+            return all;
+        }
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => { return resp; }
+        };
 
-        let neighbours = self.tree.nearest_n::<SquaredEuclidean>(seed, count);
+        let neighbours = match self.metric {
+            Metric::Manhattan => tree.nearest_n::<Manhattan>(seed, count),
+            Metric::Cosine => tree.nearest_n::<SquaredEuclidean>(&normalised(seed), count),
+            _ => tree.nearest_n::<SquaredEuclidean>(seed, count),
+        };
         for neighbour in &neighbours {
             let item = Sim {
                 id:  self.idmap[neighbour.item as usize],
-                sim: neighbour.distance,
+                sim: match self.metric {
+                    Metric::Euclidean => neighbour.distance.sqrt(),
+                    Metric::Cosine => neighbour.distance / 2.0,
+                    _ => neighbour.distance,
+                },
             };
             resp.push(item);
         }
         resp
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::point;
+
+    #[test]
+    fn distances() {
+        let a = point(&[0.0, 0.0]);
+        let b = point(&[3.0, -4.0]);
+        assert_eq!(Metric::SquaredEuclidean.distance(&a, &b), 25.0);
+        assert_eq!(Metric::Euclidean.distance(&a, &b), 5.0);
+        assert_eq!(Metric::Manhattan.distance(&a, &b), 7.0);
+        assert_eq!(Metric::Chebyshev.distance(&a, &b), 4.0);
+    }
+
+    #[test]
+    fn cosine() {
+        let a = point(&[1.0, 0.0]);
+        assert!(Metric::Cosine.distance(&a, &point(&[5.0, 0.0])).abs() < 1e-6);
+        assert!((Metric::Cosine.distance(&a, &point(&[0.0, 2.0])) - 1.0).abs() < 1e-6);
+        assert!((Metric::Cosine.distance(&a, &point(&[-1.0, 0.0])) - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_zero_vectors() {
+        let zero = point(&[]);
+        assert_eq!(Metric::Cosine.distance(&zero, &zero), 0.0);
+        let dist = Metric::Cosine.distance(&zero, &point(&[1.0, 0.0]));
+        assert!(dist.is_finite());
+        assert!((dist - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Metric::parse("Cosine"), Some(Metric::Cosine));
+        assert_eq!(Metric::parse("unknown"), None);
+    }
+}
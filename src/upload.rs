@@ -5,7 +5,8 @@
  * GPLv3 license.
  *
  **/
-use actix_web::{web, HttpRequest, Responder};
+use crate::library;
+use actix_web::{web, HttpRequest, HttpResponse};
 use rusqlite::Connection;
 use std::fs;
 use std::io::Write;
@@ -13,11 +14,43 @@ use std::path::Path;
 
 const CHUNK_SIZE: usize = 5 * 1024 * 1024;
 
-pub async fn handle_upload(req: HttpRequest, body: web::Bytes) -> impl Responder {
+// Check that file is an SQLite database containing analysis results
+fn is_valid_db(path: &Path) -> bool {
+    match Connection::open(path) {
+        Ok(conn) => {
+            let valid = match conn.query_row("SELECT COUNT(*) FROM TracksV2;", [], |row| row.get::<_, i64>(0)) {
+                Ok(_) => true,
+                Err(e) => {
+                    log::error!("{} is not a valid analysis database. {}", path.to_string_lossy(), e);
+                    false
+                }
+            };
+            // now close, so that file can be renamed
+            if conn.close().is_err() {
+                log::error!("Failed to close {}.", path.to_string_lossy());
+                return false;
+            }
+            valid
+        }
+        Err(e) => {
+            log::error!("Failed to open {}. {}", path.to_string_lossy(), e);
+            false
+        }
+    }
+}
+
+fn remove_temp(path: &Path) {
+    if path.exists() {
+        if let Err(e) = fs::remove_file(path) {
+            log::error!("Failed to remove {}. {}", path.to_string_lossy(), e);
+        }
+    }
+}
+
+pub async fn handle_upload(req: HttpRequest, body: web::Bytes) -> HttpResponse {
     let db_path = req.app_data::<web::Data<String>>().unwrap().to_string();
     let path = format!("{}.tmp", db_path);
     let up_path = Path::new(&path);
-    let orig_path = Path::new(&db_path);
     let mut total_written = 0;
 
     if up_path.exists() {
@@ -44,53 +77,21 @@ pub async fn handle_upload(req: HttpRequest, body: web::Bytes) -> impl Responder
     }
 
     log::debug!("Total size: {}", total_written);
-    // Rename DB.tmp to DB - but only if its a valid SQLite database
-    if total_written > 0 && up_path.exists() {
-        // Ensure file is a valid SQLite database
-        match Connection::open(up_path) {
-            Ok(conn) => {
-                // now close, so that file can be renamed
-                match conn.close() {
-                    Ok(_) => {
-                        // Remove original DB
-                        if orig_path.exists() {
-                            match fs::remove_file(orig_path) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    log::error!("Failed to remove {}. {}", orig_path.to_string_lossy(), e);
-                                }
-                            }
-                        }
-
-                        // Now do actual rename
-                        if !orig_path.exists() {
-                            match fs::rename(up_path, orig_path) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    log::error!("Failed to rename {} to {}. {}", up_path.to_string_lossy(), orig_path.to_string_lossy(), e);
-                                }
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        log::error!("Failed to close {}.", up_path.to_string_lossy());
-                    }
-                }
-            }
-            Err(e) => {
-                log::error!("Failed to open {}. {}", up_path.to_string_lossy(), e);
-            }
-        }
+    // Replace DB with DB.tmp - but only if its a valid SQLite database
+    if total_written == 0 || !up_path.exists() || !is_valid_db(up_path) {
+        remove_temp(up_path);
+        return HttpResponse::BadRequest().body("0");
+    }
 
-        // To be safe, remove temp if it still exists
-        if up_path.exists() {
-            match fs::remove_file(up_path) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Failed to remove {}. {}", up_path.to_string_lossy(), e);
-                }
-            }
+    // Load new DB, and then move into place, before responding
+    let library = req.app_data::<web::Data<library::Library>>().unwrap().clone();
+    let new_path = path.clone();
+    match web::block(move || library.replace(&new_path)).await {
+        Ok(_) => HttpResponse::Ok().body("1"),
+        Err(e) => {
+            log::error!("Failed to load uploaded database. {}", e);
+            remove_temp(up_path);
+            HttpResponse::BadRequest().body("0")
         }
     }
-    ""
 }
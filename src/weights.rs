@@ -0,0 +1,155 @@
+/**
+ * BlissMixer: Use Bliss analysis results to create music mixes
+ *
+ * Copyright (c) 2022-2026 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::error::Error;
+use crate::tree;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+pub type Weights = [f32; tree::DIMENSIONS];
+
+pub const DEFAULT_WEIGHTS: Weights = [1.0; tree::DIMENSIONS];
+
+// Named weights, and (optionally) the distance metric to use with them
+#[derive(Clone, Copy)]
+pub struct Profile {
+    pub weights: Weights,
+    pub metric: Option<tree::Metric>,
+}
+
+// Profile as stored in profiles file, or config. Either a comma separated list of
+// weights, or an object with (optional) weights and metric.
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ProfileEntry {
+    Weights(String),
+    Full { weights: Option<String>, metric: Option<String> },
+}
+
+// Create weights from list of values. Missing values default to 1.0
+pub fn from_values(vals: &[f32]) -> Result<Weights, Error> {
+    if vals.len() > tree::DIMENSIONS {
+        return Err(Error::InvalidWeights(format!("Too many values, expected at most {}", tree::DIMENSIONS)));
+    }
+    let mut weights = DEFAULT_WEIGHTS;
+    for (pos, val) in vals.iter().enumerate() {
+        if !val.is_finite() || *val < 0.0 {
+            return Err(Error::InvalidWeights(format!("'{}' is not a valid weight", val)));
+        }
+        weights[pos] = *val;
+    }
+    Ok(weights)
+}
+
+// Parse comma separated list of weights
+pub fn parse(weights_str: &str) -> Result<Weights, Error> {
+    let mut vals: Vec<f32> = Vec::new();
+    for val in weights_str.split(",") {
+        vals.push(val.trim().parse::<f32>().map_err(|e| Error::InvalidWeights(format!("'{}' {}", val, e)))?);
+    }
+    let weights = from_values(&vals)?;
+    log::debug!("Weights: {:?}", weights);
+    Ok(weights)
+}
+
+// Load named weight profiles from JSON file, e.g.
+// { "timbre-heavy": "0.5,1,2,2,2,2,2,2,1,1,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5,0.5",
+//   "cosine": { "metric": "cosine" } }
+pub fn load_profiles(path: &str) -> Result<HashMap<String, Profile>, Error> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::InvalidWeights(format!("Failed to read {}. {}", path, e)))?;
+    let entries: HashMap<String, ProfileEntry> = serde_json::from_str(&contents).map_err(|e| Error::InvalidWeights(format!("Failed to parse {}. {}", path, e)))?;
+    parse_profiles(&entries)
+}
+
+// Parse map of profile name to weights, and metric
+pub fn parse_profiles(entries: &HashMap<String, ProfileEntry>) -> Result<HashMap<String, Profile>, Error> {
+    let mut profiles = HashMap::new();
+    for (name, entry) in entries {
+        log::debug!("Profile '{}'", name);
+        let profile = match entry {
+            ProfileEntry::Weights(val) => Profile { weights: parse(val)?, metric: None },
+            ProfileEntry::Full { weights, metric } => Profile {
+                weights: match weights {
+                    Some(val) => parse(val)?,
+                    None => DEFAULT_WEIGHTS,
+                },
+                metric: match metric {
+                    Some(val) => Some(tree::Metric::parse(val).ok_or_else(|| Error::InvalidWeights(format!("Unknown metric '{}'", val)))?),
+                    None => None,
+                },
+            },
+        };
+        profiles.insert(name.clone(), profile);
+    }
+    Ok(profiles)
+}
+
+pub fn apply(vals: &[f32; tree::DIMENSIONS], weights: &Weights) -> [f32; tree::DIMENSIONS] {
+    let mut adjusted: [f32; tree::DIMENSIONS] = [0.0; tree::DIMENSIONS];
+    for (i, x) in vals.iter().enumerate() {
+        adjusted[i] = x * weights[i];
+    }
+    adjusted
+}
+
+// Used to identify trees built with these weights, and metric
+pub fn key(weights: &Weights, metric: tree::Metric) -> Vec<u32> {
+    let mut key: Vec<u32> = weights.iter().map(|w| w.to_bits()).collect();
+    key.push(metric as u32);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(json: &str) -> Result<HashMap<String, Profile>, Error> {
+        let entries: HashMap<String, ProfileEntry> = serde_json::from_str(json).unwrap();
+        parse_profiles(&entries)
+    }
+
+    #[test]
+    fn parse_weights() {
+        let weights = parse("0.5, 2").unwrap();
+        assert_eq!(weights[0], 0.5);
+        assert_eq!(weights[1], 2.0);
+        assert!(weights[2..].iter().all(|w| *w == 1.0));
+    }
+
+    #[test]
+    fn parse_invalid_weights() {
+        assert!(parse("").is_err());
+        assert!(parse("1,x").is_err());
+        assert!(parse("1,-1").is_err());
+        assert!(parse("nan").is_err());
+        assert!(parse("inf").is_err());
+        assert!(parse(&vec!["1"; tree::DIMENSIONS + 1].join(",")).is_err());
+        assert!(parse(&vec!["1"; tree::DIMENSIONS].join(",")).is_ok());
+    }
+
+    #[test]
+    fn parse_valid_profiles() {
+        let parsed = profiles(r#"{ "tempo": "3", "cosine": { "metric": "cosine" }, "both": { "weights": "0,2", "metric": "manhattan" } }"#).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed["tempo"].weights[0], 3.0);
+        assert!(parsed["tempo"].metric.is_none());
+        assert_eq!(parsed["cosine"].weights, DEFAULT_WEIGHTS);
+        assert_eq!(parsed["cosine"].metric, Some(tree::Metric::Cosine));
+        assert_eq!(parsed["both"].weights[1], 2.0);
+        assert_eq!(parsed["both"].metric, Some(tree::Metric::Manhattan));
+    }
+
+    #[test]
+    fn parse_malformed_profiles() {
+        assert!(profiles(r#"{ "bad": "1,two" }"#).is_err());
+        assert!(profiles(r#"{ "bad": { "weights": "-1" } }"#).is_err());
+        assert!(profiles(r#"{ "bad": { "metric": "hamming" } }"#).is_err());
+        assert!(serde_json::from_str::<HashMap<String, ProfileEntry>>(r#"{ "bad": 5 }"#).is_err());
+    }
+}